@@ -2,12 +2,15 @@
 # Populates the OpenShaiya archive database, from a local copy of the
 # archive files.
 ########################################################################
+import argparse
 import sqlite3
 import os
+import glob
 import re
 import datetime
 import configparser
 import zlib
+import hashlib
 
 # The regex for finding a patches number, and date.
 PATCH_REGEX = re.compile(r"ps(\d{4})-(\d{1,2})-(\d{1,2})-(\d{4})")
@@ -51,17 +54,37 @@ INSERT OR IGNORE INTO files (distribution, patch, path, date, fileid) VALUES (
 
 # The query for inserting to the filedata table.
 INSERT_FILEDATA_QUERY = """
-INSERT OR IGNORE INTO filedata (checksum, uncompressed_size, key) VALUES (
+INSERT OR IGNORE INTO filedata (checksum, uncompressed_size, key, sha256) VALUES (
+            ?,
             ?,
             ?,
             ?
         );
 """
 
+# The schema migrations, shared with the client builder. These are the only history of the schema
+# beyond the Flyway baseline, and are tracked with `PRAGMA user_version` just as the client builder does.
+MIGRATIONS_PATH = os.path.join(os.path.dirname(__file__), "../tools/clientbuilder/migrations")
+
+# The queries for rebuilding the indexes after the bulk load, shared with the client builder.
+REBUILD_INDEXES_PATH = os.path.join(os.path.dirname(__file__), "../tools/clientbuilder/queries/rebuild_indexes.sql")
+
 # The query for selecting from the filedata table.
 SELECT_FILEDATA_QUERY = "SELECT id FROM filedata WHERE checksum = ?;"
 
 
+# Applies any outstanding schema migrations to the database.
+def migrate(connection):
+    version = connection.execute("PRAGMA user_version;").fetchone()[0]
+    migrations = sorted(glob.glob(os.path.join(MIGRATIONS_PATH, "*.sql")))
+    for target, path in enumerate(migrations, start=1):
+        if target <= version:
+            continue
+        print(f"applying database migration {target} ({os.path.basename(path)})")
+        with open(path) as migration:
+            connection.executescript(f"BEGIN;\n{migration.read()}\nPRAGMA user_version = {target};\nCOMMIT;")
+
+
 # Collects a distribution's files.
 def collect_distribution(absroot, path, fullclient):
     entries = []
@@ -150,12 +173,13 @@ def populate_database(connection, dists, entries):
         infile = open(entry["abspath"], "rb")
         data = infile.read()
         crc32 = zlib.crc32(data)
+        sha256 = hashlib.sha256(data).hexdigest()
         infile.close()
         uncompressed_size = len(data)
         key = entry["key"]
 
         # Insert the file data.
-        cursor.execute(INSERT_FILEDATA_QUERY, (crc32, uncompressed_size, key))
+        cursor.execute(INSERT_FILEDATA_QUERY, (crc32, uncompressed_size, key, sha256))
         connection.commit()
 
         # Get the filedata id
@@ -174,8 +198,19 @@ def populate_database(connection, dists, entries):
 
 
 if __name__ == "__main__":
+    parser = argparse.ArgumentParser(description="Populates the archive database from a local copy of the archive.")
+    parser.add_argument("archive", help="the root of the local copy of the archive")
+    parser.add_argument("--database", default="../archive.sqlite", help="the path to the archive database")
+    args = parser.parse_args()
+
+    # The root of the archive, with a trailing separator so that distribution paths can be appended.
+    ARCHIVE = os.path.join(args.archive, "")
+
     # Connect to the database
-    connection = sqlite3.connect("../archive.sqlite")
+    connection = sqlite3.connect(args.database)
+
+    # Bring the schema up-to-date before loading any files.
+    migrate(connection)
 
     # Populate the data from the US base client into all `original` distributions, as patch 0.
     baseclient = collect_base(ARCHIVE, ARCHIVE+"shaiya-us/original", 0, datetime.datetime(2007, 12, 18))
@@ -211,3 +246,7 @@ if __name__ == "__main__":
         print(f"sheesh {path} {date} {id}")
         cursor.execute(INSERT_FILE_QUERY, ('ga', 0, path, date, id))
     connection.commit()
+
+    # Rebuild the indexes, now that the files have been loaded.
+    with open(REBUILD_INDEXES_PATH) as rebuild_indexes:
+        connection.executescript(rebuild_indexes.read())
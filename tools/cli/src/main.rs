@@ -0,0 +1,352 @@
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use clientbuilder::{ArchiveCompression, BuildOptions, BuildStage, Distribution, ProgressCallback};
+use common::config::{resolve, Config};
+use indicatif::{ProgressBar, ProgressStyle};
+use patchinflate::{ExtractLimits, InflateOptions, DEFAULT_MAX_ENTRY_SIZE, DEFAULT_MAX_TOTAL_SIZE};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tracing_subscriber::EnvFilter;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Cli {
+    /// The config file to read default settings from.
+    #[clap(short, long, value_parser, global = true)]
+    config: Option<PathBuf>,
+
+    /// The number of threads to use. Defaults to the number of cores.
+    #[clap(short, long, value_parser, global = true)]
+    threads: Option<usize>,
+
+    /// Log debug output.
+    #[clap(short, long, value_parser, global = true)]
+    verbose: bool,
+
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Inflate a directory of downloaded patches.
+    Inflate {
+        /// The directory containing the patch files.
+        #[clap(short, long, value_parser)]
+        patch_dir: Option<PathBuf>,
+
+        /// The directory to extract the patch files to.
+        #[clap(short, long, value_parser)]
+        inflate_dir: Option<PathBuf>,
+
+        /// The distribution the patches belong to. Detected from the patch directory if omitted.
+        #[clap(short, long, value_parser)]
+        dist: Option<Distribution>,
+
+        /// Reconcile inflated directories that share a patch number but have different dates.
+        #[clap(long, value_parser)]
+        dedup: bool,
+
+        /// A file name of the game client, matched case-insensitively. Can be passed more than
+        /// once. Defaults to `game.exe` and `shaiya.exe`.
+        #[clap(long = "client-name", value_parser)]
+        client_names: Vec<String>,
+
+        /// Re-inflate patches that have already been inflated, rather than skipping them.
+        #[clap(long, value_parser)]
+        force: bool,
+
+        /// The most a single patch can be extracted to, in bytes.
+        #[clap(long, value_parser, default_value_t = DEFAULT_MAX_TOTAL_SIZE)]
+        max_size: u64,
+
+        /// The most a single file in a patch can be extracted to, in bytes.
+        #[clap(long, value_parser, default_value_t = DEFAULT_MAX_ENTRY_SIZE)]
+        max_entry_size: u64,
+    },
+
+    /// Build a client for a distribution and patch.
+    Build {
+        /// The path to the archive database.
+        #[clap(long, value_parser)]
+        database: Option<PathBuf>,
+
+        /// The root of the local copy of the archive.
+        #[clap(short, long, value_parser)]
+        archive: Option<PathBuf>,
+
+        /// The client distribution.
+        #[clap(short, long, value_parser)]
+        dist: Distribution,
+
+        /// The patch to build. The nearest available patch at or below this is used.
+        #[clap(short, long, value_parser)]
+        patch: u16,
+
+        /// The directory to write the built client to. Defaults to the current directory.
+        #[clap(short, long, value_parser)]
+        out_dir: Option<PathBuf>,
+
+        /// The server address to point the client at.
+        #[clap(long, value_parser)]
+        address: Option<String>,
+
+        /// The login server port to point the client at. Defaults to the standard port.
+        #[clap(long, value_parser)]
+        port: Option<u16>,
+
+        /// The compression to apply to the client tarball (`gzip`, `zstd` or `xz`).
+        #[clap(long, value_parser, default_value = "gzip")]
+        compression: ArchiveCompression,
+
+        /// The compression level. Defaults to a fast level; use the maximum for long-lived builds.
+        #[clap(long, value_parser)]
+        level: Option<u32>,
+
+        /// Build an incremental update from this patch, containing only the files that changed.
+        #[clap(long, value_parser)]
+        base: Option<u16>,
+
+        /// Fail the build if a source file's size differs from the database, rather than warning.
+        #[clap(long, value_parser)]
+        strict: bool,
+
+        /// A value to set in `config.ini`, as `SECTION.KEY=VALUE` (i.e. `VIDEO.FULLSCREEN=TRUE`).
+        /// Can be passed more than once.
+        #[clap(long = "config-override", value_parser = parse_config_override)]
+        config_overrides: Vec<(String, String, String)>,
+
+        /// A directory to cache built game data in, so that rebuilding from the same files reuses
+        /// it.
+        #[clap(long, value_parser)]
+        data_cache: Option<PathBuf>,
+
+        /// A glob pattern of paths to leave out of the client (i.e. `data/map/debug*`). Can be
+        /// passed more than once.
+        #[clap(long, value_parser)]
+        exclude: Vec<String>,
+
+        /// A file to use as the `gsconfig.cfg` template, in place of the built-in one. `{address}`
+        /// and `{port}` are replaced with the server address and port.
+        #[clap(long, value_parser)]
+        gsconfig_template: Option<PathBuf>,
+
+        /// A file to use as the `version.ini` template, in place of the built-in one. `{patch}` is
+        /// replaced with the patch number.
+        #[clap(long, value_parser)]
+        version_template: Option<PathBuf>,
+    },
+
+    /// Import a local copy of the archive into the archive database, by running the import script.
+    Import {
+        /// The path to the archive database.
+        #[clap(long, value_parser)]
+        database: Option<PathBuf>,
+
+        /// The root of the local copy of the archive.
+        #[clap(short, long, value_parser)]
+        archive: Option<PathBuf>,
+
+        /// The import script. Defaults to the one in this checkout.
+        #[clap(long, value_parser, default_value = IMPORT_SCRIPT)]
+        script: PathBuf,
+
+        /// The Python interpreter to run the import script with.
+        #[clap(long, value_parser, default_value = "python3")]
+        python: String,
+    },
+
+    /// Migrate the archive database to the latest schema version. The other commands (and the
+    /// client builder lambda) migrate it when they open it, so this is only needed ahead of time.
+    Migrate {
+        /// The path to the archive database.
+        #[clap(long, value_parser)]
+        database: Option<PathBuf>,
+    },
+}
+
+/// The script that populates the archive database from a local copy of the archive.
+const IMPORT_SCRIPT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/../../scripts/populate_database_from_archive.py"
+);
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let default_level = if cli.verbose { "debug" } else { "info" };
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(default_level)),
+        )
+        .init();
+
+    let config = Config::load_or_default(cli.config.as_deref())?;
+    let threads = cli.threads.or(config.concurrency).unwrap_or(0);
+
+    match cli.command {
+        Command::Inflate {
+            patch_dir,
+            inflate_dir,
+            dist,
+            dedup,
+            client_names,
+            force,
+            max_size,
+            max_entry_size,
+        } => {
+            let output = patchinflate::inflate(&InflateOptions {
+                patch_dir: resolve(patch_dir, config.patch_dir, "patch_dir")?,
+                inflate_dir: resolve(inflate_dir, config.inflate_dir, "inflate_dir")?,
+                dist,
+                threads,
+                client_names,
+                force,
+                limits: ExtractLimits {
+                    max_total_size: max_size,
+                    max_entry_size,
+                },
+            })?;
+
+            if dedup {
+                for reconciliation in patchinflate::dedup_patches(&output.patch_dir)? {
+                    println!("{}", reconciliation);
+                }
+            }
+        }
+        Command::Build {
+            database,
+            archive,
+            dist,
+            patch,
+            out_dir,
+            address,
+            port,
+            compression,
+            level,
+            base,
+            strict,
+            config_overrides,
+            data_cache,
+            exclude,
+            gsconfig_template,
+            version_template,
+        } => {
+            let database = resolve(database, config.database, "database")?;
+            let archive = resolve(archive, config.archive_path, "archive")?;
+            let out_dir = match out_dir {
+                Some(dir) => dir,
+                None => std::env::current_dir()?,
+            };
+
+            if let Some(address) = address.as_deref() {
+                if !clientbuilder::is_valid_address(address) {
+                    return Err(anyhow::anyhow!("invalid server address `{}`", address));
+                }
+            }
+
+            let read_template = |path: Option<PathBuf>| {
+                path.map(|path| {
+                    fs::read_to_string(&path)
+                        .with_context(|| format!("failed to read template {:?}", path))
+                })
+                .transpose()
+            };
+            let gsconfig_template = read_template(gsconfig_template)?;
+            let version_template = read_template(version_template)?;
+
+            let conn = clientbuilder::init_db(&database)?;
+            let patch = clientbuilder::normalize_patch(&conn, dist, patch)?;
+            let mut overrides: BTreeMap<String, BTreeMap<String, String>> = BTreeMap::new();
+            for (section, key, value) in config_overrides {
+                overrides.entry(section).or_default().insert(key, value);
+            }
+
+            // Show the progress of the build, restarting the bar at each stage.
+            let bar = ProgressBar::new(0);
+            bar.set_style(
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} {msg}",
+                )?
+                .progress_chars("##-"),
+            );
+            let progress = ProgressCallback(Arc::new({
+                let bar = bar.clone();
+                move |stage: BuildStage, current, total| {
+                    bar.set_message(stage.to_string());
+                    bar.set_length(total);
+                    bar.set_position(current);
+                }
+            }));
+
+            let gsconfig_values = port
+                .map(|port| BTreeMap::from([("port".to_string(), port.to_string())]))
+                .unwrap_or_default();
+            let options = BuildOptions {
+                address,
+                threads,
+                compression,
+                compression_level: level,
+                base,
+                strict,
+                config_overrides: overrides,
+                data_cache,
+                exclude,
+                gsconfig_template,
+                gsconfig_values,
+                version_template,
+                progress: Some(progress),
+                ..Default::default()
+            };
+            let result =
+                clientbuilder::build_client(&conn, &out_dir, &archive, dist, patch, &options)
+                    .await?;
+            bar.finish_and_clear();
+            println!("{}", result.path.display());
+        }
+        Command::Import {
+            database,
+            archive,
+            script,
+            python,
+        } => {
+            let database = resolve(database, config.database, "database")?;
+            let archive = resolve(archive, config.archive_path, "archive")?;
+            let status = std::process::Command::new(&python)
+                .arg(&script)
+                .arg(&archive)
+                .arg("--database")
+                .arg(&database)
+                .status()
+                .with_context(|| format!("failed to run import script {:?}", script))?;
+            if !status.success() {
+                return Err(anyhow::anyhow!(
+                    "import script {:?} failed: {}",
+                    script,
+                    status
+                ));
+            }
+        }
+        Command::Migrate { database } => {
+            let database = resolve(database, config.database, "database")?;
+            clientbuilder::init_db(&database)?;
+        }
+    }
+    Ok(())
+}
+
+/// Parses a `config.ini` override, in the form `SECTION.KEY=VALUE`.
+///
+/// # Arguments
+/// * `value`   - The override.
+fn parse_config_override(value: &str) -> Result<(String, String, String), String> {
+    let (name, value) = value
+        .split_once('=')
+        .ok_or_else(|| format!("expected `SECTION.KEY=VALUE`, got `{}`", value))?;
+    let (section, key) = name
+        .split_once('.')
+        .ok_or_else(|| format!("expected `SECTION.KEY`, got `{}`", name))?;
+    Ok((section.to_string(), key.to_string(), value.to_string()))
+}
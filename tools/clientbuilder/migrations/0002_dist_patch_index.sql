@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS files_distribution_patch ON files (distribution, patch);
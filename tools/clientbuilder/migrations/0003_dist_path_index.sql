@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS files_distribution_path ON files (distribution, path, patch);
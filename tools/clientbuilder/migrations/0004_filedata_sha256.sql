@@ -0,0 +1 @@
+ALTER TABLE filedata ADD COLUMN sha256 text;
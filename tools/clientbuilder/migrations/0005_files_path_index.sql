@@ -0,0 +1 @@
+CREATE INDEX IF NOT EXISTS files_path ON files (path, distribution, patch);
@@ -0,0 +1 @@
+SELECT distribution, max(patch) FROM files GROUP BY distribution ORDER BY distribution
\ No newline at end of file
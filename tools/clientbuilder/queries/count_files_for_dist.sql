@@ -0,0 +1,3 @@
+SELECT COUNT(DISTINCT file.path) FROM files file
+    INNER JOIN filedata data on data.id = file.fileid
+    WHERE file.distribution = ? AND file.patch <= ?;
@@ -0,0 +1,4 @@
+SELECT file.patch, data.key, file.date FROM files file
+    INNER JOIN filedata data on data.id = file.fileid
+    WHERE file.distribution = ? AND file.path = ?
+    ORDER BY file.patch ASC;
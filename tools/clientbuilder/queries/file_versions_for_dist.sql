@@ -0,0 +1,4 @@
+SELECT file.path, data.key, data.uncompressed_size, file.date, data.sha256, file.patch FROM files file
+    INNER JOIN filedata data on data.id = file.fileid
+    WHERE file.distribution = ? AND file.patch <= ?
+    ORDER BY file.path, file.patch;
@@ -1,7 +1,8 @@
-SELECT path, key, uncompressed_size, date FROM (
-    SELECT row_number() over (partition by file.path ORDER BY patch desc) rows, file.patch, file.path, file.date, data.checksum, data.uncompressed_size, data.key FROM files file
+SELECT path, key, uncompressed_size, date, sha256 FROM (
+    SELECT row_number() over (partition by file.path ORDER BY patch desc) rows, file.patch, file.path, file.date, data.checksum, data.uncompressed_size, data.key, data.sha256 FROM files file
         INNER JOIN filedata data on data.id = file.fileid
         WHERE file.distribution = ? AND file.patch <= ?
-        GROUP BY file.patch, file.path, data.checksum, data.uncompressed_size, data.key
+        GROUP BY file.patch, file.path, data.checksum, data.uncompressed_size, data.key, data.sha256
         ORDER BY file.patch DESC
-) groups WHERE groups.rows <= 1;
\ No newline at end of file
+) groups WHERE groups.rows <= 1
+ORDER BY path;
\ No newline at end of file
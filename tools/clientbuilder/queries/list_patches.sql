@@ -0,0 +1 @@
+SELECT DISTINCT patch FROM files WHERE distribution = ? ORDER BY patch
\ No newline at end of file
@@ -0,0 +1,2 @@
+REINDEX files;
+ANALYZE;
@@ -0,0 +1,5 @@
+SELECT path, distribution, min(patch), max(patch) FROM files
+    WHERE path >= ?1 AND path < ?1 || char(1114111)
+    GROUP BY path, distribution
+    ORDER BY path, distribution
+    LIMIT ?2;
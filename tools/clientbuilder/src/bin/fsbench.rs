@@ -0,0 +1,157 @@
+use anyhow::anyhow;
+use clap::Parser;
+use std::fs;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// The number of generated files placed in each directory.
+const FILES_PER_DIR: usize = 256;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The number of files to generate.
+    #[clap(short, long, value_parser, default_value_t = 10000)]
+    files: usize,
+
+    /// The total size of the generated files, in megabytes.
+    #[clap(short, long, value_parser, default_value_t = 256)]
+    megabytes: u64,
+
+    /// The directory to run the benchmark in. Defaults to the system temp directory.
+    #[clap(short, long, value_parser)]
+    work_dir: Option<PathBuf>,
+}
+
+/// A generated file, which can be regenerated from its seed to verify the round-trip.
+struct SyntheticFile {
+    path: PathBuf,
+    seed: u64,
+    len: usize,
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+    if args.files == 0 {
+        return Err(anyhow!("at least one file is required"));
+    }
+
+    let root = args
+        .work_dir
+        .unwrap_or_else(std::env::temp_dir)
+        .join(format!("fsbench-{}", Uuid::new_v4()));
+    let result = run(&root, args.files, args.megabytes * 1024 * 1024);
+    fs::remove_dir_all(&root)?;
+    result
+}
+
+fn run(root: &Path, count: usize, total: u64) -> anyhow::Result<()> {
+    let source = root.join("data");
+    let files = generate(&source, count, total)?;
+    println!(
+        "generated {} files ({} bytes) in {:?}",
+        count, total, source
+    );
+
+    // Build the archive from the generated directory.
+    let header_path = root.join("bench.sah");
+    let data_path = root.join("bench.saf");
+    let start = Instant::now();
+    let mut header = File::create(&header_path)?;
+    let mut data = Vec::with_capacity(total as usize);
+    let fs = libclient::fs::Filesystem::from_path(&source)?;
+    fs.build_with_destination(&mut header, &mut data)?;
+    let build_elapsed = start.elapsed();
+    fs::write(&data_path, &data)?;
+    drop(data);
+    report("build", total, build_elapsed);
+
+    // Extract the archive back out.
+    let extract_path = root.join("extract");
+    let start = Instant::now();
+    let fs = libclient::fs::Filesystem::from_archive(&header_path, &data_path)?;
+    fs.extract(&extract_path)?;
+    report("extract", total, start.elapsed());
+
+    // Confirm every file survived the round-trip intact.
+    let mismatched = files
+        .iter()
+        .filter(|file| {
+            let expected = synthesize(file.seed, file.len);
+            fs::read(extract_path.join(&file.path))
+                .map(|actual| actual != expected)
+                .unwrap_or(true)
+        })
+        .map(|file| file.path.display().to_string())
+        .collect::<Vec<_>>();
+    if !mismatched.is_empty() {
+        return Err(anyhow!(
+            "{} files didn't round-trip: {}",
+            mismatched.len(),
+            mismatched.join(", ")
+        ));
+    }
+    println!("round-trip verified");
+    Ok(())
+}
+
+/// Generates a directory of files with pseudo-random contents.
+///
+/// # Arguments
+/// * `dir`     - The directory to generate the files in.
+/// * `count`   - The number of files to generate.
+/// * `total`   - The total size of the files, in bytes.
+fn generate(dir: &Path, count: usize, total: u64) -> anyhow::Result<Vec<SyntheticFile>> {
+    let base_len = (total / count as u64) as usize;
+    let remainder = (total % count as u64) as usize;
+    (0..count)
+        .map(|idx| {
+            let path = PathBuf::from(format!("dir{:04}", idx / FILES_PER_DIR))
+                .join(format!("file{:06}.bin", idx));
+            let len = base_len + usize::from(idx < remainder);
+            let seed = idx as u64 + 1;
+
+            let out = dir.join(&path);
+            fs::create_dir_all(out.parent().unwrap())?;
+            fs::write(&out, synthesize(seed, len))?;
+            Ok(SyntheticFile { path, seed, len })
+        })
+        .collect()
+}
+
+/// Generates deterministic pseudo-random bytes, using an xorshift generator.
+///
+/// # Arguments
+/// * `seed`    - The non-zero generator seed.
+/// * `len`     - The number of bytes to generate.
+fn synthesize(seed: u64, len: usize) -> Vec<u8> {
+    let mut state = seed;
+    let mut buf = Vec::with_capacity(len + 8);
+    while buf.len() < len {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        buf.extend_from_slice(&state.to_le_bytes());
+    }
+    buf.truncate(len);
+    buf
+}
+
+/// Prints the throughput of a benchmark phase.
+///
+/// # Arguments
+/// * `phase`   - The name of the phase.
+/// * `bytes`   - The number of bytes processed.
+/// * `elapsed` - The time the phase took.
+fn report(phase: &str, bytes: u64, elapsed: Duration) {
+    let megabytes = bytes as f64 / (1024.0 * 1024.0);
+    println!(
+        "{}: {:.2?} ({:.2} MiB/s)",
+        phase,
+        elapsed,
+        megabytes / elapsed.as_secs_f64()
+    );
+}
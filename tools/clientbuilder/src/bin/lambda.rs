@@ -1,119 +1,1016 @@
+use anyhow::anyhow;
+use aws_sdk_s3::client::fluent_builders::PutObject;
+use aws_sdk_s3::presigning::config::PresigningConfig;
+use aws_sdk_s3::types::SdkError;
 use aws_smithy_http::byte_stream::ByteStream;
-use clientbuilder::{build_client, Distribution, AWS_S3_BUCKET};
-use lambda_http::http::StatusCode;
+use clientbuilder::{
+    build_client, ArchiveCompression, BuildOptions, Distribution, UnavailablePatch, AWS_S3_BUCKET,
+};
+use lambda_http::http::header::{
+    HeaderValue, ACCESS_CONTROL_ALLOW_HEADERS, ACCESS_CONTROL_ALLOW_METHODS,
+    ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_MAX_AGE,
+};
+use lambda_http::http::{Method, StatusCode};
 use lambda_http::{service_fn, Body, Error, IntoResponse, Request, RequestExt, Response};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use sqlite::Connection;
+use std::collections::BTreeMap;
+use std::fmt::Display;
 use std::fs;
-use std::path::Path;
+use std::future::Future;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+use tracing::{field, Instrument, Span};
+use tracing_subscriber::EnvFilter;
 
-/// The base s3 url where files are stored.
-const ARCHIVE_URL: &str = "https://s3.amazonaws.com/archive.openshaiya.org";
+/// The base s3 url where files are stored, if `ARCHIVE_URL` isn't set.
+const DEFAULT_ARCHIVE_URL: &str = "https://s3.amazonaws.com/archive.openshaiya.org";
 
-/// The object key for the sqlite database.
-const DATABASE_KEY: &str = "api/archive.sqlite";
+/// The object key for the sqlite database, if `DATABASE_KEY` isn't set.
+const DEFAULT_DATABASE_KEY: &str = "api/archive.sqlite";
+
+/// The origin allowed to call the lambda from a browser, if `CORS_ORIGIN` isn't set.
+const DEFAULT_CORS_ORIGIN: &str = "https://archive.openshaiya.org";
+
+/// The number of attempts made at an s3 operation, if `S3_MAX_ATTEMPTS` isn't set.
+const DEFAULT_S3_MAX_ATTEMPTS: u32 = 3;
+
+/// The delay before the first retry of a failed s3 operation, which doubles on each retry.
+const S3_RETRY_BASE_DELAY: Duration = Duration::from_millis(200);
+
+/// The fewest files a client can have before it's considered incomplete, if `MIN_FILE_COUNT`
+/// isn't set. A real client has several thousand.
+const DEFAULT_MIN_FILE_COUNT: usize = 1000;
+
+/// The configured time limit of the lambda, if `FUNCTION_TIMEOUT` isn't set. This is the most
+/// AWS allows.
+const DEFAULT_FUNCTION_TIMEOUT: Duration = Duration::from_secs(900);
+
+/// How long before the lambda's time limit a build is abandoned, leaving time to clean up and
+/// respond before AWS terminates the function.
+const BUILD_TIMEOUT_MARGIN: Duration = Duration::from_secs(5);
+
+/// How long a presigned client url is valid for, if `PRESIGN_TTL` isn't set.
+const DEFAULT_PRESIGN_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The key of the object metadata holding a client's SHA-256.
+const SHA256_METADATA_KEY: &str = "sha256";
+
+/// The cache policy for a built client. A client object is never rewritten once built for a
+/// dist and patch, so it can be cached indefinitely.
+const CLIENT_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
 
 #[derive(Deserialize)]
 struct SRequest {
     dist: Distribution,
     patch: u16,
+    /// The compression of the client tarball. Defaults to gzip.
+    #[serde(default)]
+    format: ArchiveCompression,
+    /// The compression level of the client tarball. Defaults to a fast level.
+    #[serde(default)]
+    level: Option<u32>,
+    /// The server address the client connects to. Defaults to `127.0.0.1`.
+    #[serde(default)]
+    address: Option<String>,
+    /// Values to set in the client's `config.ini`, keyed by section and then key.
+    #[serde(default)]
+    config: BTreeMap<String, BTreeMap<String, String>>,
 }
 
 #[derive(Serialize)]
 struct SResponse {
     url: String,
     elapsed: Duration,
+    phase_timings: BTreeMap<&'static str, Duration>,
+    /// The SHA-256 of the client tarball, if it's known. Clients uploaded before digests were
+    /// recorded don't have one.
+    sha256: Option<String>,
+    /// The patch the client was built for, after normalising the requested patch.
+    patch: u16,
+    /// The number of files in the client, if it was built by this request.
+    file_count: Option<usize>,
+    /// The total uncompressed size of the client's files, if it was built by this request.
+    uncompressed_size: Option<u64>,
+    /// The modification time of the client's files, if it was built by this request.
+    mtime: Option<u64>,
+}
+
+/// The response to a cache check, which reports whether a client has already been built without
+/// ever building it.
+#[derive(Serialize)]
+struct SCheckResponse {
+    url: String,
+    exists: bool,
+    size: Option<i64>,
+    sha256: Option<String>,
+}
+
+/// An error response, describing why a request failed.
+#[derive(Serialize)]
+struct SError {
+    error: String,
 }
 
 impl IntoResponse for SResponse {
     fn into_response(self) -> Response<Body> {
-        let body = serde_json::to_string(&self).unwrap();
-        Response::builder()
-            .status(StatusCode::OK)
-            .body(Body::Text(body))
-            .unwrap()
+        json_response(StatusCode::OK, &self)
+    }
+}
+
+impl IntoResponse for SCheckResponse {
+    fn into_response(self) -> Response<Body> {
+        json_response(StatusCode::OK, &self)
+    }
+}
+
+/// Creates an error response, with a message describing the failure.
+///
+/// # Arguments
+/// * `status`  - The status code of the response.
+/// * `error`   - The error message.
+fn error_response(status: StatusCode, error: impl Into<String>) -> Response<Body> {
+    json_response(
+        status,
+        &SError {
+            error: error.into(),
+        },
+    )
+}
+
+/// Creates a 500 response for a request that failed on the server's side, logging the error.
+///
+/// # Arguments
+/// * `context` - What was being done when the error occurred.
+/// * `error`   - The error.
+fn internal_error(context: &str, error: impl Display) -> Response<Body> {
+    tracing::error!(%error, "{}", context);
+    error_response(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        format!("{}: {}", context, error),
+    )
+}
+
+/// Serializes a value as the JSON body of a response.
+///
+/// # Arguments
+/// * `status`  - The status code of the response.
+/// * `value`   - The value to serialize.
+fn json_response<T: Serialize>(status: StatusCode, value: &T) -> Response<Body> {
+    let body = serde_json::to_string(value).unwrap();
+    Response::builder()
+        .status(status)
+        .body(Body::Text(body))
+        .unwrap()
+}
+
+/// The settings of the lambda, read from the environment at startup.
+struct Settings {
+    /// The s3 bucket that built clients are uploaded to.
+    bucket: String,
+    /// The base url that objects in the bucket are served from.
+    archive_url: String,
+    /// The key of the sqlite database, relative to the archive path.
+    database_key: String,
+    /// The path to the local copy of the archive.
+    archive_path: PathBuf,
+    /// The number of attempts made at an s3 operation before giving up.
+    s3_max_attempts: u32,
+    /// The fewest files a client can have before the archive is considered incomplete.
+    min_file_count: usize,
+    /// The time budget of a request, after which a build is abandoned.
+    build_timeout: Duration,
+    /// The directory to cache built game data in, shared between builds on the same mount.
+    data_cache: Option<PathBuf>,
+    /// How long the returned client urls are valid for, if they're presigned. When not set, the
+    /// urls are built from `archive_url`, which relies on the bucket being public.
+    presign_ttl: Option<Duration>,
+    /// The origin allowed to call the lambda from a browser.
+    cors_origin: HeaderValue,
+}
+
+impl Settings {
+    /// Reads the settings from the environment, falling back to the public archive's values.
+    fn from_env() -> anyhow::Result<Self> {
+        let var =
+            |name: &str, default: &str| std::env::var(name).unwrap_or_else(|_| default.to_string());
+
+        let bucket = var("S3_BUCKET", AWS_S3_BUCKET);
+        if bucket.trim().is_empty() {
+            return Err(anyhow!("`S3_BUCKET` must not be empty"));
+        }
+
+        let cors_origin = var("CORS_ORIGIN", DEFAULT_CORS_ORIGIN);
+        let cors_origin = HeaderValue::from_str(&cors_origin)
+            .map_err(|e| anyhow!("invalid `CORS_ORIGIN`: {}", e))?;
+
+        Ok(Self {
+            bucket,
+            archive_url: var("ARCHIVE_URL", DEFAULT_ARCHIVE_URL)
+                .trim_end_matches('/')
+                .to_string(),
+            database_key: var("DATABASE_KEY", DEFAULT_DATABASE_KEY),
+            archive_path: PathBuf::from(std::env::var("ARCHIVE_PATH")?),
+            s3_max_attempts: var("S3_MAX_ATTEMPTS", &DEFAULT_S3_MAX_ATTEMPTS.to_string())
+                .parse()
+                .map_err(|e| anyhow!("invalid `S3_MAX_ATTEMPTS`: {}", e))?,
+            min_file_count: var("MIN_FILE_COUNT", &DEFAULT_MIN_FILE_COUNT.to_string())
+                .parse()
+                .map_err(|e| anyhow!("invalid `MIN_FILE_COUNT`: {}", e))?,
+            build_timeout: Duration::from_secs(
+                var(
+                    "FUNCTION_TIMEOUT",
+                    &DEFAULT_FUNCTION_TIMEOUT.as_secs().to_string(),
+                )
+                .parse()
+                .map_err(|e| anyhow!("invalid `FUNCTION_TIMEOUT`: {}", e))?,
+            )
+            .saturating_sub(BUILD_TIMEOUT_MARGIN),
+            data_cache: std::env::var("DATA_CACHE_DIR").ok().map(PathBuf::from),
+            presign_ttl: match var("PRESIGN_URLS", "false").as_str() {
+                "true" => Some(Duration::from_secs(
+                    var("PRESIGN_TTL", &DEFAULT_PRESIGN_TTL.as_secs().to_string())
+                        .parse()
+                        .map_err(|e| anyhow!("invalid `PRESIGN_TTL`: {}", e))?,
+                )),
+                _ => None,
+            },
+            cors_origin,
+        })
+    }
+}
+
+/// The state of the lambda, which outlives a single invocation so that warm invocations don't
+/// repeat the setup work.
+struct State {
+    /// The lambda settings.
+    settings: Settings,
+    /// The s3 client.
+    s3_client: aws_sdk_s3::Client,
+    /// The database connection left idle by the last request, if there is one. A connection isn't
+    /// `Sync`, so a request takes it for its whole duration, and opens its own if it's missing.
+    conn: Mutex<Option<Connection>>,
+}
+
+impl State {
+    /// Takes the idle database connection, or opens a new one if there isn't one. The connection
+    /// is returned to the state when the guard is dropped. The database is migrated when it's
+    /// first opened, which is safe while other instances of the lambda are doing the same.
+    fn connection(&self) -> anyhow::Result<ConnectionGuard<'_>> {
+        let conn = match self.conn.lock().unwrap().take() {
+            Some(conn) => conn,
+            None => {
+                tracing::info!("opening database connection");
+                let settings = &self.settings;
+                clientbuilder::init_db(&settings.archive_path.join(&settings.database_key))?
+            }
+        };
+        Ok(ConnectionGuard {
+            slot: &self.conn,
+            conn: Some(conn),
+        })
+    }
+}
+
+/// A database connection taken from the lambda state, which is returned to it when dropped. If
+/// the connection is taken out of the guard (i.e. to move it to a build thread), it's only
+/// returned if it's put back.
+struct ConnectionGuard<'a> {
+    slot: &'a Mutex<Option<Connection>>,
+    conn: Option<Connection>,
+}
+
+impl Deref for ConnectionGuard<'_> {
+    type Target = Connection;
+
+    fn deref(&self) -> &Connection {
+        self.conn.as_ref().expect("connection taken from guard")
+    }
+}
+
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take() {
+            *self.slot.lock().unwrap() = Some(conn);
+        }
     }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
-    tracing_subscriber::fmt::init();
-    let func = service_fn(handler);
-    lambda_http::run(func).await.unwrap();
-    Ok(())
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(EnvFilter::from_default_env())
+        .init();
+    let aws_config = aws_config::load_from_env().await;
+    let state = Arc::new(State {
+        settings: Settings::from_env()?,
+        s3_client: aws_sdk_s3::Client::new(&aws_config),
+        conn: Mutex::new(None),
+    });
+    let func = service_fn(move |req| handler(req, state.clone()));
+    lambda_http::run(func).await
 }
 
-async fn handler(http_req: Request) -> Result<impl IntoResponse, Error> {
-    let req: SRequest = http_req.payload().unwrap_or(None).unwrap();
+async fn handler(http_req: Request, state: Arc<State>) -> Result<Response<Body>, Error> {
+    // Browsers send a preflight request before calling the lambda cross-origin, which only needs
+    // the CORS headers in response.
+    let mut response = if http_req.method() == Method::OPTIONS {
+        Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .body(Body::Empty)?
+    } else {
+        serve(http_req, &state).await?
+    };
 
-    // Initialise an s3 client.
-    let aws_config = aws_config::load_from_env().await;
-    let s3_client = aws_sdk_s3::Client::new(&aws_config);
+    let headers = response.headers_mut();
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_ORIGIN,
+        state.settings.cors_origin.clone(),
+    );
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_METHODS,
+        HeaderValue::from_static("GET, POST, OPTIONS"),
+    );
+    headers.insert(
+        ACCESS_CONTROL_ALLOW_HEADERS,
+        HeaderValue::from_static("Content-Type"),
+    );
+    headers.insert(ACCESS_CONTROL_MAX_AGE, HeaderValue::from_static("86400"));
+    Ok(response)
+}
+
+/// Serves a build request.
+///
+/// # Arguments
+/// * `http_req`    - The http request.
+/// * `state`       - The lambda state.
+async fn serve(http_req: Request, state: &State) -> Result<Response<Body>, Error> {
+    let req = match parse_request(&http_req) {
+        Ok(req) => req,
+        Err(e) => return Ok(error_response(StatusCode::BAD_REQUEST, e)),
+    };
+    let check = http_req.query_string_parameters().first("check") == Some("true");
+
+    let span = request_span(&req, check);
+    let result = handle(req, check, state).instrument(span.clone()).await;
+    span.in_scope(|| {
+        let response = match result {
+            Ok(response) => response,
+            Err(e) => internal_error("request failed", e),
+        };
+        tracing::info!(status = response.status().as_u16(), "request complete");
+        Ok(response)
+    })
+}
+
+/// Creates the span covering a request, with a field for everything logged about it. The fields
+/// that aren't known when the request is parsed are recorded as it's handled.
+///
+/// # Arguments
+/// * `req`     - The build request.
+/// * `check`   - Whether the request only checks if the client has been built.
+fn request_span(req: &SRequest, check: bool) -> Span {
+    tracing::info_span!(
+        "request",
+        dist = %req.dist,
+        patch = req.patch,
+        address = field::Empty,
+        check,
+        cache_hit = field::Empty,
+        build_ms = field::Empty,
+        upload_ms = field::Empty,
+        artifact_size = field::Empty,
+        data_buf_peak = field::Empty,
+        misc_bytes_read = field::Empty,
+        file_count = field::Empty,
+        uncompressed_size = field::Empty,
+    )
+}
+
+/// Handles a parsed build request, within the request's span.
+///
+/// # Arguments
+/// * `req`         - The build request.
+/// * `check`       - Whether to only check if the client has been built, without building it.
+/// * `state`       - The lambda state.
+async fn handle(req: SRequest, check: bool, state: &State) -> Result<Response<Body>, Error> {
+    let span = Span::current();
+    if let Some(address) = &req.address {
+        span.record("address", address.as_str());
+    }
+    let (settings, s3_client) = (&state.settings, &state.s3_client);
 
     // Even within the same region, downloading thousands of files from S3 is painfully slow. To
     // circumvent this, we have mounted a local copy of the archive in an EFS filesystem, and
     // will be used that to read the data.
-    let archive_path = std::env::var("ARCHIVE_PATH")?;
-    let efs_path = Path::new(&archive_path);
+    let efs_path = settings.archive_path.as_path();
     let tmp = std::env::temp_dir();
 
-    // Initialise the database.
-    let conn = init_db(efs_path).await?;
+    // Take the database connection, which is kept open between warm invocations.
+    let mut conn = state.connection()?;
     let time = Instant::now();
 
-    // Normalise the patch number and get the object key.
-    let patch = clientbuilder::normalize_patch(&conn, req.dist, req.patch)?;
-    let key = format!(
-        "api/build/{}.tar.gz",
-        clientbuilder::object_name(req.dist, patch)
-    );
-    let url = format!("{}/{}", ARCHIVE_URL, &key);
+    let cancel = Arc::new(AtomicBool::new(false));
+    let options = BuildOptions {
+        verify: true,
+        cancel: Some(cancel.clone()),
+        compression: req.format,
+        compression_level: req.level,
+        address: req.address.clone(),
+        config_overrides: req.config.clone(),
+        data_cache: settings.data_cache.clone(),
+        ..Default::default()
+    };
+    let compression = options.compression;
+    if let Some(level) = req.level.filter(|&level| level > compression.max_level()) {
+        return Ok(error_response(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "compression level must be at most {}, got {}",
+                compression.max_level(),
+                level
+            ),
+        ));
+    }
+
+    // Normalise the patch number and get the object key. A dist or patch the archive has nothing
+    // for is the caller's mistake, so it's reported before doing any more work. Any other error
+    // is a failure to query the database.
+    let patch = match clientbuilder::normalize_patch(&conn, req.dist, req.patch) {
+        Ok(patch) => patch,
+        Err(e) => {
+            return Ok(match e.downcast_ref::<UnavailablePatch>() {
+                Some(unavailable) => error_response(StatusCode::NOT_FOUND, unavailable.to_string()),
+                None => internal_error("failed to normalize patch", format!("{:#}", e)),
+            })
+        }
+    };
+    span.record("patch", patch);
+    // Clients pointed at a server other than the default are cached separately, under a
+    // directory named after the address, and clients with config overrides are named after a
+    // digest of the overrides.
+    let mut name = clientbuilder::object_name(req.dist, patch);
+    if !req.config.is_empty() {
+        let digest = format!("{:x}", Sha256::digest(serde_json::to_vec(&req.config)?));
+        name = format!("{}-{}", name, &digest[..12]);
+    }
+    let key = match &req.address {
+        Some(address) => format!("api/build/{}/{}.{}", address, name, compression.extension()),
+        None => format!("api/build/{}.{}", name, compression.extension()),
+    };
+    let url = client_url(s3_client, settings, &key).await?;
+
+    let head = with_retry(settings.s3_max_attempts, || {
+        s3_client
+            .head_object()
+            .bucket(&settings.bucket)
+            .key(&key)
+            .send()
+    })
+    .await;
+
+    span.record("cache_hit", head.is_ok());
+
+    let sha256 = head
+        .as_ref()
+        .ok()
+        .and_then(|h| h.metadata())
+        .and_then(|metadata| metadata.get(SHA256_METADATA_KEY))
+        .cloned();
+
+    // If this is only a cache check, report whether the client exists without building it.
+    if check {
+        let size = head.as_ref().ok().map(|h| h.content_length());
+        return Ok(SCheckResponse {
+            url,
+            exists: head.is_ok(),
+            size,
+            sha256,
+        }
+        .into_response());
+    }
 
     // If a file with the specified key already exists, we can just return with that file.
-    if (s3_client
-        .head_object()
-        .bucket(AWS_S3_BUCKET)
-        .key(&key)
-        .send()
-        .await)
-        .is_ok()
-    {
+    if head.is_ok() {
         return Ok(SResponse {
             url,
             elapsed: time.elapsed(),
-        });
+            phase_timings: BTreeMap::new(),
+            sha256,
+            patch,
+            file_count: None,
+            uncompressed_size: None,
+            mtime: None,
+        }
+        .into_response());
     }
 
-    // Build the client
-    let client = build_client(&conn, &tmp, efs_path, req.dist, patch, None)
-        .await
-        .unwrap();
-    let metadata = fs::metadata(&client).unwrap();
-    let stream = ByteStream::from_path(&client).await.unwrap();
-    tracing::info!(?client, len = metadata.len(), "built client; uploading");
+    // Gaps left by a failed download would otherwise produce a client that's missing files, so
+    // refuse to build from an incomplete archive.
+    let file_count = clientbuilder::file_count(&conn, req.dist, patch).await?;
+    if file_count < settings.min_file_count {
+        return Ok(error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!(
+                "archive incomplete for dist {} patch {} (only {} files)",
+                req.dist, patch, file_count
+            ),
+        ));
+    }
 
-    // Upload the client
-    s3_client
-        .put_object()
-        .bucket(AWS_S3_BUCKET)
-        .key(&key)
-        .body(stream)
-        .send()
+    // Build the client. The build is mostly blocking work, so it runs on its own thread where it
+    // can be abandoned if it runs past the time budget, rather than being killed by AWS. The
+    // connection moves to the build thread, and is only put back if the build finishes in time.
+    let build_time = Instant::now();
+    let build = tokio::task::spawn_blocking({
+        let (dist, efs_path, build_conn) = (req.dist, efs_path.to_path_buf(), conn.conn.take());
+        move || {
+            let build_conn = build_conn.unwrap();
+            let result = Handle::current().block_on(build_client(
+                &build_conn,
+                &tmp,
+                &efs_path,
+                dist,
+                patch,
+                &options,
+            ));
+            (build_conn, result)
+        }
+    });
+    let budget = settings.build_timeout.saturating_sub(time.elapsed());
+    let result = match tokio::time::timeout(budget, build).await {
+        Ok(Ok((build_conn, result))) => {
+            conn.conn = Some(build_conn);
+            result
+        }
+        Ok(Err(e)) => return Ok(internal_error("build task failed", e)),
+        Err(_) => {
+            // Cancelling the build removes its temporary directory once it reaches the next phase.
+            cancel.store(true, Ordering::Relaxed);
+            tracing::warn!(budget_ms = budget.as_millis() as u64, "build timed out");
+            return Ok(error_response(
+                StatusCode::GATEWAY_TIMEOUT,
+                format!(
+                    "build exceeded time budget for dist {} patch {}",
+                    req.dist, patch
+                ),
+            ));
+        }
+    };
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => return Ok(internal_error("failed to build client", format!("{:#}", e))),
+    };
+    span.record("build_ms", build_time.elapsed().as_millis() as u64);
+    span.record("data_buf_peak", result.data_buf_peak as u64);
+    span.record("misc_bytes_read", result.misc_bytes_read);
+    span.record("file_count", result.file_count);
+    span.record("uncompressed_size", result.uncompressed_size);
+    let (file_count, uncompressed_size, mtime) =
+        (result.file_count, result.uncompressed_size, result.mtime);
+    let client = result.path;
+    let mut phase_timings = result.phase_timings;
+    let uploaded = async {
+        let metadata =
+            fs::metadata(&client).map_err(|e| internal_error("failed to read built client", e))?;
+        span.record("artifact_size", metadata.len());
+
+        // Record the digest of the client, so that downloads of it can be verified.
+        let sha256 =
+            sha256_file(&client).map_err(|e| internal_error("failed to hash built client", e))?;
+        tracing::info!(?client, len = metadata.len(), %sha256, "built client; uploading");
+
+        // Upload the client
+        let upload_time = Instant::now();
+        let (client_path, key_ref, sha256_ref) = (&client, &key, &sha256);
+        let content_type = compression.content_type();
+        with_retry(settings.s3_max_attempts, move || async move {
+            // The body is consumed by each attempt, so it's reopened on every retry.
+            let stream = ByteStream::from_path(client_path)
+                .await
+                .map_err(|e| SdkError::ConstructionFailure(Box::new(e)))?;
+            put_client(
+                s3_client,
+                &settings.bucket,
+                key_ref,
+                content_type,
+                sha256_ref,
+            )
+            .body(stream)
+            .send()
+            .await
+        })
         .await
-        .unwrap();
+        .map_err(|e| internal_error("failed to upload client", e))?;
+        phase_timings.insert("upload", upload_time.elapsed());
+        span.record("upload_ms", upload_time.elapsed().as_millis() as u64);
+        Ok::<_, Response<Body>>(sha256)
+    }
+    .await;
+
+    // The client is served from s3 once it's uploaded, so the local copy is removed whether or not
+    // the upload succeeded, rather than filling the temporary directory across warm invocations.
+    if let Err(e) = fs::remove_file(&client) {
+        tracing::warn!(?client, "failed to remove built client: {}", e);
+    }
+    let sha256 = match uploaded {
+        Ok(sha256) => sha256,
+        Err(response) => return Ok(response),
+    };
 
     Ok(SResponse {
         url,
         elapsed: time.elapsed(),
+        phase_timings,
+        sha256: Some(sha256),
+        patch,
+        file_count: Some(file_count),
+        uncompressed_size: Some(uncompressed_size),
+        mtime: Some(mtime),
+    }
+    .into_response())
+}
+
+/// Creates the request to upload a built client, with its headers and metadata but without its
+/// body. Clients are named by their contents, so they're served with a long-lived cache policy.
+///
+/// # Arguments
+/// * `s3_client`       - The s3 client.
+/// * `bucket`          - The bucket to upload to.
+/// * `key`             - The object key of the client.
+/// * `content_type`    - The content type of the client tarball.
+/// * `sha256`          - The SHA-256 of the client.
+fn put_client(
+    s3_client: &aws_sdk_s3::Client,
+    bucket: &str,
+    key: &str,
+    content_type: &str,
+    sha256: &str,
+) -> PutObject {
+    s3_client
+        .put_object()
+        .bucket(bucket)
+        .key(key)
+        .content_type(content_type)
+        .cache_control(CLIENT_CACHE_CONTROL)
+        .metadata(SHA256_METADATA_KEY, sha256)
+}
+
+/// Computes the SHA-256 of a file, streaming it rather than reading it into memory.
+///
+/// # Arguments
+/// * `path`    - The path to the file.
+fn sha256_file(path: &Path) -> std::io::Result<String> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Gets the url a built client can be downloaded from. This is presigned if the lambda is
+/// configured with a `presign_ttl`, and otherwise is a public url under `archive_url`.
+///
+/// # Arguments
+/// * `s3_client`   - The s3 client.
+/// * `settings`    - The lambda settings.
+/// * `key`         - The object key of the client.
+async fn client_url(
+    s3_client: &aws_sdk_s3::Client,
+    settings: &Settings,
+    key: &str,
+) -> anyhow::Result<String> {
+    let ttl = match settings.presign_ttl {
+        Some(ttl) => ttl,
+        None => return Ok(format!("{}/{}", settings.archive_url, key)),
+    };
+
+    let presigned = s3_client
+        .get_object()
+        .bucket(&settings.bucket)
+        .key(key)
+        .presigned(PresigningConfig::expires_in(ttl)?)
+        .await?;
+    Ok(presigned.uri().to_string())
+}
+
+/// Runs an s3 operation, retrying it with exponential backoff if it fails with a transient error.
+///
+/// # Arguments
+/// * `attempts`    - The maximum number of attempts to make.
+/// * `op`          - The operation to run.
+async fn with_retry<T, E, F, Fut>(attempts: u32, mut op: F) -> Result<T, SdkError<E>>
+where
+    E: std::error::Error + 'static,
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, SdkError<E>>>,
+{
+    let mut delay = S3_RETRY_BASE_DELAY;
+    let mut attempt = 1;
+    loop {
+        match op().await {
+            Err(e) if attempt < attempts && is_retryable(&e) => {
+                tracing::warn!(attempt, error = %e, "s3 operation failed; retrying");
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Checks if a failed s3 operation is worth retrying. Only timeouts, connection failures, and
+/// throttling or server errors are retried; any other response (such as the "not found" returned
+/// by `head_object` for a client that hasn't been built) is final.
+///
+/// # Arguments
+/// * `err` - The error the operation failed with.
+fn is_retryable<E>(err: &SdkError<E>) -> bool {
+    match err {
+        SdkError::TimeoutError(_) | SdkError::DispatchFailure(_) => true,
+        SdkError::ResponseError { .. } => true,
+        SdkError::ServiceError { raw, .. } => {
+            let status = raw.http().status();
+            status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+        }
+        SdkError::ConstructionFailure(_) => false,
+    }
+}
+
+/// Parses the build request, from either the JSON body or the query string parameters (i.e.
+/// `GET /?dist=us&patch=430&format=zstd&level=9&address=play.example.com`).
+///
+/// # Arguments
+/// * `http_req`    - The http request.
+fn parse_request(http_req: &Request) -> Result<SRequest, String> {
+    let payload = http_req.payload::<SRequest>();
+    if let Ok(Some(req)) = payload {
+        return validate_request(req);
+    }
+
+    let params = http_req.query_string_parameters();
+    let dist = match (params.first("dist"), payload) {
+        (Some(dist), _) => dist,
+        (None, Err(e)) => return Err(format!("invalid request body: {}", e)),
+        (None, Ok(_)) => return Err("missing required parameter `dist`".to_string()),
+    };
+    let dist = dist
+        .parse::<Distribution>()
+        .map_err(|_| format!("unknown distribution `{}`", dist))?;
+    let patch = params
+        .first("patch")
+        .ok_or_else(|| "missing required parameter `patch`".to_string())?;
+    let patch = patch
+        .parse::<u16>()
+        .map_err(|_| format!("invalid patch number `{}`", patch))?;
+    let format = params
+        .first("format")
+        .map(|format| {
+            format
+                .parse::<ArchiveCompression>()
+                .map_err(|_| format!("unknown compression format `{}`", format))
+        })
+        .transpose()?
+        .unwrap_or_default();
+    let level = params
+        .first("level")
+        .map(|level| {
+            level
+                .parse::<u32>()
+                .map_err(|_| format!("invalid compression level `{}`", level))
+        })
+        .transpose()?;
+    let address = params.first("address").map(str::to_string);
+    validate_request(SRequest {
+        dist,
+        patch,
+        format,
+        level,
+        address,
+        config: BTreeMap::new(),
     })
 }
 
-/// Initialise the sqlite database, from a file at a provided path.
+/// Validates the fields of a build request that can't be checked while parsing it.
 ///
 /// # Arguments
-/// * `path`    - The database path.
-async fn init_db(path: &Path) -> anyhow::Result<Connection> {
-    let db_path = path.join(DATABASE_KEY);
-    Ok(sqlite::open(&db_path)?)
+/// * `req` - The build request.
+fn validate_request(req: SRequest) -> Result<SRequest, String> {
+    if let Some(address) = req.address.as_deref() {
+        if !clientbuilder::is_valid_address(address) {
+            return Err(format!("invalid server address `{}`", address));
+        }
+    }
+    Ok(req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    /// Creates an s3 client with static credentials, which can sign requests without any network
+    /// access.
+    fn test_s3_client() -> aws_sdk_s3::Client {
+        let config = aws_sdk_s3::Config::builder()
+            .region(aws_sdk_s3::Region::new("us-east-1"))
+            .credentials_provider(aws_sdk_s3::Credentials::new(
+                "access", "secret", None, None, "test",
+            ))
+            .build();
+        aws_sdk_s3::Client::from_conf(config)
+    }
+
+    /// Creates the lambda state for a test. Nothing is read from the archive path, so requests
+    /// that get as far as the database fail.
+    fn test_state() -> State {
+        State {
+            settings: Settings {
+                bucket: AWS_S3_BUCKET.to_string(),
+                archive_url: DEFAULT_ARCHIVE_URL.to_string(),
+                database_key: DEFAULT_DATABASE_KEY.to_string(),
+                archive_path: PathBuf::from("/nonexistent"),
+                s3_max_attempts: 1,
+                min_file_count: DEFAULT_MIN_FILE_COUNT,
+                build_timeout: DEFAULT_FUNCTION_TIMEOUT,
+                data_cache: None,
+                presign_ttl: None,
+                cors_origin: HeaderValue::from_static(DEFAULT_CORS_ORIGIN),
+            },
+            s3_client: test_s3_client(),
+            conn: Mutex::new(None),
+        }
+    }
+
+    /// Creates a request with no body, and the provided query string parameters.
+    ///
+    /// # Arguments
+    /// * `params`  - The query string parameters.
+    fn query_request(params: &[(&str, &str)]) -> Request {
+        let params = params
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<HashMap<_, _>>();
+        Request::default().with_query_string_parameters(params)
+    }
+
+    #[test]
+    fn parses_query_string_request() {
+        let req = parse_request(&query_request(&[
+            ("dist", "us"),
+            ("patch", "430"),
+            ("format", "zstd"),
+            ("level", "9"),
+            ("address", "play.example.com"),
+        ]))
+        .unwrap();
+        assert_eq!(req.dist, Distribution::Us);
+        assert_eq!(req.patch, 430);
+        assert!(matches!(req.format, ArchiveCompression::Zstd));
+        assert_eq!(req.level, Some(9));
+        assert_eq!(req.address.as_deref(), Some("play.example.com"));
+    }
+
+    #[test]
+    fn rejects_query_string_request_with_missing_parameters() {
+        let error = |params: &[(&str, &str)]| parse_request(&query_request(params)).err();
+        assert_eq!(
+            error(&[("patch", "430")]).as_deref(),
+            Some("missing required parameter `dist`")
+        );
+        assert_eq!(
+            error(&[("dist", "us")]).as_deref(),
+            Some("missing required parameter `patch`")
+        );
+        assert_eq!(
+            error(&[("dist", "xx"), ("patch", "430")]).as_deref(),
+            Some("unknown distribution `xx`")
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_body_without_query_string() {
+        let http_req = Request::builder()
+            .header("content-type", "application/json")
+            .body(Body::from("{\"dist\": "))
+            .unwrap();
+        let error = parse_request(&http_req).err().unwrap();
+        assert!(error.starts_with("invalid request body: "), "{}", error);
+    }
+
+    #[tokio::test]
+    async fn invalid_request_is_bad_request() {
+        let state = test_state();
+        for params in [&[("patch", "430")][..], &[("dist", "us")][..], &[][..]] {
+            let response = serve(query_request(params), &state).await.unwrap();
+            assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[tokio::test]
+    async fn unavailable_patch_is_not_found() {
+        let conn = sqlite::open(":memory:").unwrap();
+        clientbuilder::migrate(&conn).unwrap();
+        conn.execute(
+            "INSERT INTO filedata (id, checksum, uncompressed_size, key) VALUES (1, 1, 4, 'game.exe');
+             INSERT INTO files (distribution, patch, path, date, fileid)
+                 VALUES ('us', 100, 'game.exe', '2010-01-01 00:00:00', 1);",
+        )
+        .unwrap();
+        let state = test_state();
+        *state.conn.lock().unwrap() = Some(conn);
+
+        for (dist, patch) in [("de", "100"), ("us", "50")] {
+            let http_req = query_request(&[("dist", dist), ("patch", patch)]);
+            let response = serve(http_req, &state).await.unwrap();
+            assert_eq!(
+                response.status(),
+                StatusCode::NOT_FOUND,
+                "{} {}",
+                dist,
+                patch
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn database_error_is_internal_error() {
+        // An unmigrated database has no tables, so normalizing the patch fails to query it.
+        let state = test_state();
+        *state.conn.lock().unwrap() = Some(sqlite::open(":memory:").unwrap());
+
+        let http_req = query_request(&[("dist", "us"), ("patch", "100")]);
+        let response = serve(http_req, &state).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    /// A log destination that captures everything written to it.
+    #[derive(Clone, Default)]
+    struct CaptureWriter(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for CaptureWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn request_span_has_structured_fields() {
+        let captured = CaptureWriter::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+
+        tracing::subscriber::with_default(subscriber, || {
+            let req = parse_request(&query_request(&[("dist", "us"), ("patch", "430")])).unwrap();
+            let span = request_span(&req, false);
+            span.record("address", "play.example.com");
+            span.record("cache_hit", false);
+            span.record("build_ms", 1200_u64);
+            span.record("upload_ms", 300_u64);
+            span.record("artifact_size", 4096_u64);
+            span.in_scope(|| tracing::info!("request complete"));
+        });
+
+        let output = String::from_utf8(captured.0.lock().unwrap().clone()).unwrap();
+        let line = output.lines().last().expect("no log output");
+        let event: serde_json::Value = serde_json::from_str(line).unwrap();
+        let span = &event["span"];
+        assert_eq!(span["name"], "request");
+        assert_eq!(span["dist"], "us");
+        assert_eq!(span["patch"], 430);
+        assert_eq!(span["address"], "play.example.com");
+        assert_eq!(span["check"], false);
+        assert_eq!(span["cache_hit"], false);
+        assert_eq!(span["build_ms"], 1200);
+        assert_eq!(span["upload_ms"], 300);
+        assert_eq!(span["artifact_size"], 4096);
+    }
+
+    #[tokio::test]
+    async fn client_upload_has_content_type_and_cache_control() {
+        let s3_client = test_s3_client();
+        let content_type = ArchiveCompression::Zstd.content_type();
+        let presigned = put_client(&s3_client, "bucket", "us/200.tar.zst", content_type, "abc")
+            .presigned(PresigningConfig::expires_in(Duration::from_secs(60)).unwrap())
+            .await
+            .unwrap();
+
+        let headers = presigned.headers();
+        assert_eq!(headers["content-type"], content_type);
+        assert_eq!(headers["cache-control"], CLIENT_CACHE_CONTROL);
+        assert_eq!(headers["x-amz-meta-sha256"], "abc");
+    }
 }
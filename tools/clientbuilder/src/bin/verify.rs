@@ -0,0 +1,61 @@
+use clap::Parser;
+use clientbuilder::verify::verify_client;
+use clientbuilder::Distribution;
+use common::config::{resolve, Config};
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The config file to read default settings from.
+    #[clap(short, long, value_parser)]
+    config: Option<PathBuf>,
+
+    /// The path to the archive database.
+    #[clap(short, long, value_parser)]
+    database: Option<PathBuf>,
+
+    /// The built client tarball to verify.
+    #[clap(short, long, value_parser)]
+    tarball: PathBuf,
+
+    /// The distribution the client was built for.
+    #[clap(long, value_parser)]
+    dist: Distribution,
+
+    /// The patch the client was built for.
+    #[clap(short, long, value_parser)]
+    patch: u16,
+
+    /// The directory to unpack the client into while verifying. Defaults to the system temp directory.
+    #[clap(short, long, value_parser)]
+    work_dir: Option<PathBuf>,
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let config = Config::load_or_default(args.config.as_deref())?;
+    let database = resolve(args.database, config.database, "database")?;
+
+    let conn = clientbuilder::init_db(&database)?;
+    let work_dir = args.work_dir.unwrap_or_else(std::env::temp_dir);
+    let report = verify_client(&conn, &work_dir, &args.tarball, args.dist, args.patch).await?;
+
+    for discrepancy in &report.discrepancies {
+        println!("{}", discrepancy);
+    }
+
+    if !report.passed() {
+        println!(
+            "FAIL: {} discrepancies across {} files",
+            report.discrepancies.len(),
+            report.file_count
+        );
+        std::process::exit(1);
+    }
+    println!("PASS: {} files", report.file_count);
+    Ok(())
+}
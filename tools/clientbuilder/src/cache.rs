@@ -0,0 +1,112 @@
+use crate::{ClientFile, DataFile, ManifestEntry};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use uuid::Uuid;
+
+/// The name of the cached manifest of the game data files, within a cache entry.
+const MANIFEST_NAME: &str = "manifest.json";
+
+/// Checks if a client file is game data, packed into the archive filesystem, rather than a loose
+/// file in the client root.
+///
+/// # Arguments
+/// * `file`    - The client file.
+pub(crate) fn is_data_file(file: &ClientFile) -> bool {
+    file.path.starts_with("data/")
+}
+
+/// Gets the cache key of the game data built from a set of files. Identical contents share an
+/// object key, so two builds with the same paths and keys produce the same archive.
+///
+/// # Arguments
+/// * `files`           - The client files.
+/// * `archive_name`    - The name of the archive filesystem (i.e. `data`).
+pub(crate) fn key(files: &[ClientFile], archive_name: &str) -> String {
+    let mut entries = files
+        .iter()
+        .filter(|f| is_data_file(f))
+        .map(|f| (f.path.as_str(), f.key.as_str()))
+        .collect::<Vec<_>>();
+    entries.sort_unstable();
+
+    let mut hasher = Sha256::new();
+    hasher.update(archive_name);
+    for (path, key) in entries {
+        hasher.update([0]);
+        hasher.update(path);
+        hasher.update([0]);
+        hasher.update(key);
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+/// Restores cached game data into a build directory, returning the manifest of its files.
+///
+/// # Arguments
+/// * `entry`   - The cache entry.
+/// * `header`  - The path to write the archive header to.
+/// * `data`    - The path to write the archive data to.
+pub(crate) fn restore(
+    entry: &Path,
+    header: &Path,
+    data: &Path,
+) -> anyhow::Result<BTreeMap<String, ManifestEntry>> {
+    link_or_copy(&entry.join("data.sah"), header)?;
+    link_or_copy(&entry.join("data.saf"), data)?;
+    let manifest = serde_json::from_slice(&fs::read(entry.join(MANIFEST_NAME))?)?;
+    tracing::info!(?entry, "restored game data from cache");
+    Ok(manifest)
+}
+
+/// Stores built game data in the cache. The entry is written to a temporary directory and then
+/// renamed into place, so a partially written entry is never used.
+///
+/// # Arguments
+/// * `entry`       - The cache entry.
+/// * `header`      - The path to the built archive header.
+/// * `data`        - The built archive data.
+/// * `manifest`    - The manifest of the game data files.
+pub(crate) fn store(
+    entry: &Path,
+    header: &Path,
+    data: &DataFile,
+    manifest: &BTreeMap<String, ManifestEntry>,
+) -> anyhow::Result<()> {
+    let name = entry.file_name().unwrap().to_string_lossy();
+    let tmp = entry.with_file_name(format!("{}.{}", name, Uuid::new_v4()));
+    fs::create_dir_all(&tmp)?;
+
+    let result = (|| -> anyhow::Result<()> {
+        link_or_copy(header, &tmp.join("data.sah"))?;
+        match data {
+            DataFile::Memory(data) => fs::write(tmp.join("data.saf"), data)?,
+            DataFile::Disk(path) => link_or_copy(path, &tmp.join("data.saf"))?,
+        }
+        fs::write(tmp.join(MANIFEST_NAME), serde_json::to_vec(manifest)?)?;
+        fs::rename(&tmp, entry)?;
+        Ok(())
+    })();
+
+    // If another build stored the same entry first, this one is no longer needed.
+    if result.is_err() {
+        fs::remove_dir_all(&tmp)?;
+    } else {
+        tracing::info!(?entry, "stored game data in cache");
+    }
+    result
+}
+
+/// Hard links a file, or copies it if it can't be linked (i.e. across filesystems).
+///
+/// # Arguments
+/// * `from`    - The file to link.
+/// * `to`      - The path of the link.
+fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_err() {
+        fs::copy(from, to)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,246 @@
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::io::{Read, Write};
+use std::path::Path;
+use strum_macros::{Display, EnumString, IntoStaticStr};
+use xz2::read::XzDecoder;
+use xz2::write::XzEncoder;
+
+/// The compression applied to a built client tarball.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    Display,
+    EnumString,
+    IntoStaticStr,
+    Deserialize,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
+pub enum ArchiveCompression {
+    /// gzip, which every platform can unpack. This is the default, for compatibility.
+    #[default]
+    Gzip,
+    /// zstd, which compresses game data noticeably better than gzip and decompresses faster.
+    Zstd,
+    /// xz, which gives the smallest archives but is the slowest to build.
+    Xz,
+}
+
+impl ArchiveCompression {
+    /// Every supported compression, in order of preference when detecting a tarball's format.
+    pub const ALL: [ArchiveCompression; 3] = [Self::Gzip, Self::Zstd, Self::Xz];
+
+    /// The file extension of a tarball with this compression (i.e. `tar.gz`).
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Gzip => "tar.gz",
+            Self::Zstd => "tar.zst",
+            Self::Xz => "tar.xz",
+        }
+    }
+
+    /// The highest compression level this compression supports.
+    pub fn max_level(self) -> u32 {
+        match self {
+            Self::Gzip | Self::Xz => 9,
+            Self::Zstd => 22,
+        }
+    }
+
+    /// The content type of a tarball with this compression.
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Gzip => "application/gzip",
+            Self::Zstd => "application/zstd",
+            Self::Xz => "application/x-xz",
+        }
+    }
+
+    /// Detects the compression of a tarball from its file extension.
+    ///
+    /// # Arguments
+    /// * `path`    - The path to the tarball.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        Self::ALL
+            .into_iter()
+            .find(|c| name.ends_with(&format!(".{}", c.extension())))
+    }
+
+    /// Wraps a reader in a decoder for this compression.
+    ///
+    /// # Arguments
+    /// * `reader`  - The compressed data.
+    pub fn decoder<'a, R: Read + 'a>(self, reader: R) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Self::Gzip => Box::new(GzDecoder::new(reader)),
+            Self::Zstd => Box::new(zstd::Decoder::new(reader)?),
+            Self::Xz => Box::new(XzDecoder::new(reader)),
+        })
+    }
+}
+
+/// A compressing writer, for any of the supported archive compressions.
+pub(crate) enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+    Xz(XzEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Creates an encoder. Without an explicit level, this favours speed over ratio.
+    ///
+    /// # Arguments
+    /// * `compression` - The compression to apply.
+    /// * `level`       - The compression level, up to `compression.max_level()`.
+    /// * `writer`      - The destination of the compressed data.
+    pub(crate) fn new(
+        compression: ArchiveCompression,
+        level: Option<u32>,
+        writer: W,
+    ) -> io::Result<Self> {
+        if let Some(level) = level.filter(|&level| level > compression.max_level()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{} compression level must be at most {}, got {}",
+                    compression,
+                    compression.max_level(),
+                    level
+                ),
+            ));
+        }
+
+        Ok(match compression {
+            ArchiveCompression::Gzip => Self::Gzip(GzEncoder::new(
+                writer,
+                level.map_or(flate2::Compression::fast(), flate2::Compression::new),
+            )),
+            ArchiveCompression::Zstd => {
+                Self::Zstd(zstd::Encoder::new(writer, level.unwrap_or(3) as i32)?)
+            }
+            ArchiveCompression::Xz => Self::Xz(XzEncoder::new(writer, level.unwrap_or(6))),
+        })
+    }
+
+    /// Flushes the remaining compressed data, and returns the underlying writer.
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Zstd(encoder) => encoder.finish(),
+            Self::Xz(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+            Self::Xz(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+            Self::Xz(encoder) => encoder.flush(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compresses data, and decompresses it again.
+    ///
+    /// # Arguments
+    /// * `compression` - The compression to apply.
+    /// * `level`       - The compression level.
+    /// * `data`        - The data.
+    fn round_trip(compression: ArchiveCompression, level: Option<u32>, data: &[u8]) -> Vec<u8> {
+        let mut encoder = Encoder::new(compression, level, Vec::new()).unwrap();
+        encoder.write_all(data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        let mut decompressed = Vec::new();
+        compression
+            .decoder(compressed.as_slice())
+            .unwrap()
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        decompressed
+    }
+
+    #[test]
+    fn round_trips_every_compression() {
+        let data = b"OpenShaiya archive ".repeat(4096);
+        for compression in ArchiveCompression::ALL {
+            for level in [None, Some(1)] {
+                assert_eq!(
+                    round_trip(compression, level, &data),
+                    data,
+                    "{} at level {:?}",
+                    compression,
+                    level
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn round_trips_tarball() {
+        for compression in ArchiveCompression::ALL {
+            let mut tar = tar::Builder::new(Encoder::new(compression, None, Vec::new()).unwrap());
+            let mut header = tar::Header::new_gnu();
+            header.set_size(5);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, "game.exe", &b"hello"[..])
+                .unwrap();
+            let compressed = tar.into_inner().unwrap().finish().unwrap();
+
+            let mut archive =
+                tar::Archive::new(compression.decoder(compressed.as_slice()).unwrap());
+            let mut entries = archive.entries().unwrap();
+            let mut entry = entries.next().unwrap().unwrap();
+            assert_eq!(entry.path().unwrap().to_str(), Some("game.exe"));
+            let mut contents = String::new();
+            entry.read_to_string(&mut contents).unwrap();
+            assert_eq!(contents, "hello");
+            drop(entry);
+            assert!(entries.next().is_none());
+        }
+    }
+
+    #[test]
+    fn rejects_level_above_maximum() {
+        for compression in ArchiveCompression::ALL {
+            let level = Some(compression.max_level() + 1);
+            assert!(Encoder::new(compression, level, Vec::new()).is_err());
+        }
+    }
+
+    #[test]
+    fn detects_compression_from_path() {
+        for compression in ArchiveCompression::ALL {
+            let path = format!("us-430.{}", compression.extension());
+            assert_eq!(
+                ArchiveCompression::from_path(Path::new(&path)),
+                Some(compression)
+            );
+        }
+        assert_eq!(ArchiveCompression::from_path(Path::new("us-430.tar")), None);
+    }
+}
@@ -1,107 +1,778 @@
-use anyhow::anyhow;
+use crate::compression::Encoder;
+use anyhow::{anyhow, Context};
 use chrono::NaiveDateTime;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use glob::{MatchOptions, Pattern};
 use ini::Ini;
 use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
 use serde::{Deserialize, Serialize};
-use sqlite::{Connection, State};
+use sha2::{Digest, Sha256};
+use sqlite::{Connection, State, Statement, Value};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::File;
-use std::io::Write;
+use std::future::Future;
+use std::io;
+use std::io::{BufWriter, Read, Write};
+use std::net::IpAddr;
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use strum_macros::{Display, IntoStaticStr};
 use tar::{Builder, EntryType, Header};
+use tracing::Instrument;
 use uuid::Uuid;
 
+mod cache;
+mod compression;
+pub mod verify;
+
+pub use common::Distribution;
+pub use compression::ArchiveCompression;
+
 pub const AWS_S3_BUCKET: &str = "archive.openshaiya.org";
 
 pub const GSCONFIG_TEMPLATE: &str = include_str!("../gsconfig.template.cfg");
 
 pub const VERSION_TEMPLATE: &str = include_str!("../version.template.ini");
 
-#[derive(Clone, Copy, PartialEq, Eq, Display, IntoStaticStr, Deserialize, Serialize)]
-#[strum(serialize_all = "lowercase")]
-#[serde(rename_all = "snake_case")]
-pub enum Distribution {
-    Us,
-    De,
-    Es,
-    Pt,
-    Ga,
-}
+/// The port of the login server, written to `gsconfig.cfg` unless another is supplied.
+pub const DEFAULT_SERVER_PORT: u16 = 30800;
+
+/// The rough ratio of a built client's compressed size to the uncompressed size of its files, used
+/// to estimate download sizes. Most of a client is already-compressed textures and models, so gzip
+/// only manages a modest reduction.
+pub const ESTIMATED_COMPRESSION_RATIO: f64 = 0.7;
+
+/// How long a database connection waits for another to release its lock, in milliseconds.
+const DB_BUSY_TIMEOUT_MS: usize = 30_000;
+
+/// The schema migrations for the archive database, in the order they should be applied. The
+/// index of a migration (plus one) is the `user_version` the database has once it is applied.
+const MIGRATIONS: &[&str] = &[
+    include_str!("../migrations/0001_init.sql"),
+    include_str!("../migrations/0002_dist_patch_index.sql"),
+    include_str!("../migrations/0003_dist_path_index.sql"),
+    include_str!("../migrations/0004_filedata_sha256.sql"),
+    include_str!("../migrations/0005_files_path_index.sql"),
+];
 
+#[derive(Clone)]
 struct ClientFile {
     path: String,
     key: String,
     uncompressed_size: i64,
     epoch: u64,
+    sha256: Option<String>,
+}
+
+/// A file in a client's `manifest.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ManifestEntry {
+    /// The size of the file, in bytes.
+    size: u64,
+    /// The SHA-256 of the file.
+    sha256: String,
+}
+
+/// A stage of a build, reported to a progress callback.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Display, IntoStaticStr)]
+#[strum(serialize_all = "snake_case")]
+pub enum BuildStage {
+    /// Collecting the client's files from the database. Counted in files.
+    Collecting,
+    /// Populating the build directory from the archive. Counted in files.
+    Populating,
+    /// Compressing the game data into the tarball. Counted in bytes.
+    CompressingData,
+    /// Appending the loose files to the tarball. Counted in files.
+    AppendingFiles,
+}
+
+/// A callback which is told the progress of a build, as the current stage and the current and
+/// total counts within it.
+#[derive(Clone)]
+pub struct ProgressCallback(pub Arc<dyn Fn(BuildStage, u64, u64) + Send + Sync>);
+
+impl std::fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+/// The options for building a client.
+#[derive(Debug, Clone, Default)]
+pub struct BuildOptions {
+    /// The server address to write to `gsconfig.cfg`. Defaults to `127.0.0.1`.
+    pub address: Option<String>,
+    /// The number of threads to populate the client with. `0` uses one thread per core.
+    pub threads: usize,
+    /// Whether to read the built `data.sah`/`data.saf` back, and check it contains every populated
+    /// file before packaging it.
+    pub verify: bool,
+    /// The template to write `version.ini` from. Defaults to `VERSION_TEMPLATE`.
+    pub version_template: Option<String>,
+    /// Extra values to substitute into the `{...}` placeholders of the `version.ini` template.
+    /// `{patch}` is always available, but can be overridden here.
+    pub version_values: BTreeMap<String, String>,
+    /// The template to write `gsconfig.cfg` from. Defaults to `GSCONFIG_TEMPLATE`.
+    pub gsconfig_template: Option<String>,
+    /// Extra values to substitute into the `{...}` placeholders of the `gsconfig.cfg` template.
+    /// `{address}` is taken from `address`, and `{port}` defaults to `DEFAULT_SERVER_PORT`, unless
+    /// overridden here.
+    pub gsconfig_values: BTreeMap<String, String>,
+    /// Values to set in `config.ini`, keyed by section and then key. These are applied on top of
+    /// the defaults the build sets (i.e. `VIDEO`/`FULLSCREEN` = `FALSE`).
+    pub config_overrides: BTreeMap<String, BTreeMap<String, String>>,
+    /// Whether to verify each file read from the archive against the SHA-256 recorded in the
+    /// database, failing the build on a mismatch.
+    pub verify_checksums: bool,
+    /// Whether to only build the game data. The build stops once `data.sah` and `data.saf` (or
+    /// their `update` equivalents) are written, without writing the client config or packaging a
+    /// tarball.
+    pub data_only: bool,
+    /// Whether to keep the temporary build directory once the build succeeds, for debugging.
+    pub keep_temp: bool,
+    /// The compression applied to the client tarball. Defaults to gzip.
+    pub compression: ArchiveCompression,
+    /// The compression level of the client tarball, up to `compression.max_level()`. Defaults to
+    /// a fast level, which suits on-demand builds; long-lived builds can use the maximum instead.
+    pub compression_level: Option<u32>,
+    /// A flag which cancels the build when set. The build stops at the next phase boundary and
+    /// removes its temporary directory.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The patch to build an incremental update from. When set, only the files that were added or
+    /// changed since this patch are packaged, into `update.sah` and `update.saf` (as the original
+    /// patches were) rather than a full client.
+    pub base: Option<u16>,
+    /// A callback to report the progress of the build to.
+    pub progress: Option<ProgressCallback>,
+    /// Whether to build `data.saf` in memory, rather than streaming it through a temporary file.
+    /// This is faster for small builds, but holds the whole data file in memory.
+    pub data_in_memory: bool,
+    /// A directory to cache built game data in, keyed by the files it was built from. A later build
+    /// from the same files (such as a rebuild, or a patch that didn't change the game data) reuses
+    /// the cached `data.sah` and `data.saf` rather than populating and building them again.
+    pub data_cache: Option<PathBuf>,
+    /// Whether a source file with a different size to the one recorded in the database fails the
+    /// build. Otherwise, it's only logged. A missing source file always fails the build.
+    pub strict: bool,
+    /// Glob patterns of paths to leave out of the client, matched case-insensitively against the
+    /// path relative to the client root (i.e. `data/map/debug*`). `*` also matches `/`, so
+    /// `data/interface/de/*` excludes a whole directory.
+    pub exclude: Vec<String>,
+}
+
+impl BuildOptions {
+    /// Whether the build has been cancelled through `cancel`.
+    fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .is_some_and(|cancel| cancel.load(Ordering::Relaxed))
+    }
+
+    /// Reports the progress of the build to the `progress` callback, if there is one.
+    ///
+    /// # Arguments
+    /// * `stage`   - The current stage.
+    /// * `current` - The current count within the stage.
+    /// * `total`   - The total count of the stage.
+    fn report(&self, stage: BuildStage, current: u64, total: u64) {
+        if let Some(progress) = &self.progress {
+            (progress.0)(stage, current, total);
+        }
+    }
 }
 
+/// The result of building a client.
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildResult {
+    /// The path to the built client archive. For a data-only build, this is the directory
+    /// containing `data.sah` and `data.saf`, and for a build written to a writer it's the
+    /// temporary build directory (which is removed unless `keep_temp` is set).
+    pub path: PathBuf,
+    /// The time spent in each phase of the build.
+    pub phase_timings: BTreeMap<&'static str, Duration>,
+    /// The peak size of the in-memory `data.saf` buffer, in bytes. This is `0` unless the build
+    /// was made with `data_in_memory`.
+    pub data_buf_peak: usize,
+    /// The number of bytes read from the misc (non-archive) files added to the tarball.
+    pub misc_bytes_read: u64,
+    /// The patch the client was built for.
+    pub patch: u16,
+    /// The number of files in the client, or in the update for an incremental build.
+    pub file_count: usize,
+    /// The total uncompressed size of the files, in bytes.
+    pub uncompressed_size: u64,
+    /// The most recent modification time of the files, as a unix timestamp. Every entry in the
+    /// tarball is given this time.
+    pub mtime: u64,
+}
+
+/// A built `data.saf`, before it's packaged.
+pub(crate) enum DataFile {
+    /// The data file, held in memory.
+    Memory(Vec<u8>),
+    /// The path to the data file, written to the build directory.
+    Disk(PathBuf),
+}
+
+/// A reader of the game data, which reports the bytes read through it as the progress of the
+/// `CompressingData` stage.
+struct ProgressReader<'a, R> {
+    inner: R,
+    options: &'a BuildOptions,
+    read: u64,
+    total: u64,
+}
+
+impl<'a, R: Read> ProgressReader<'a, R> {
+    fn new(inner: R, options: &'a BuildOptions, total: u64) -> Self {
+        options.report(BuildStage::CompressingData, 0, total);
+        Self {
+            inner,
+            options,
+            read: 0,
+            total,
+        }
+    }
+}
+
+impl<R: Read> Read for ProgressReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.read += len as u64;
+        self.options
+            .report(BuildStage::CompressingData, self.read, self.total);
+        Ok(len)
+    }
+}
+
+/// Records the time spent in each phase of a build, wrapping each phase in a `tracing` span.
+#[derive(Default)]
+struct PhaseTimer {
+    timings: BTreeMap<&'static str, Duration>,
+}
+
+impl PhaseTimer {
+    /// Runs a phase of the build, recording its elapsed time.
+    ///
+    /// # Arguments
+    /// * `phase`   - The name of the phase.
+    /// * `f`       - The work to run.
+    fn time<T>(&mut self, phase: &'static str, f: impl FnOnce() -> T) -> T {
+        let span = tracing::info_span!("build_phase", phase);
+        let start = Instant::now();
+        let result = span.in_scope(f);
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    /// Runs an asynchronous phase of the build, recording its elapsed time.
+    ///
+    /// # Arguments
+    /// * `phase`   - The name of the phase.
+    /// * `fut`     - The work to run.
+    async fn time_async<F: Future>(&mut self, phase: &'static str, fut: F) -> F::Output {
+        let span = tracing::info_span!("build_phase", phase);
+        let start = Instant::now();
+        let result = fut.instrument(span).await;
+        self.record(phase, start.elapsed());
+        result
+    }
+
+    fn record(&mut self, phase: &'static str, elapsed: Duration) {
+        tracing::info!(
+            phase,
+            elapsed_ms = elapsed.as_millis() as u64,
+            "build phase complete"
+        );
+        *self.timings.entry(phase).or_default() += elapsed;
+    }
+}
+
+/// Builds a client, and writes it as a compressed tarball to `dir/<name>.<extension>`, where the
+/// extension depends on the compression (i.e. `tar.gz`).
+///
+/// # Arguments
+/// * `conn`    - The database connection.
+/// * `dir`     - The directory to build the client in.
+/// * `src`     - The root of the archive to read the client files from.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+/// * `options` - The build options.
 pub async fn build_client<'a>(
     conn: &Connection,
     dir: &Path,
     src: &Path,
     dist: Distribution,
     patch: u16,
-    address: Option<String>,
-) -> anyhow::Result<PathBuf> {
-    let dest = create_temp_dir(dir, dist, patch)?;
+    options: &BuildOptions,
+) -> anyhow::Result<BuildResult> {
+    build_client_from(FileSource::Database(conn), dir, src, dist, patch, options).await
+}
+
+/// Builds a client from a source of files, and writes it as a compressed tarball to
+/// `dir/<name>.<extension>`.
+///
+/// # Arguments
+/// * `files`   - The source of the client files.
+/// * `dir`     - The directory to build the client in.
+/// * `src`     - The root of the archive to read the client files from.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+/// * `options` - The build options.
+async fn build_client_from(
+    files: FileSource<'_>,
+    dir: &Path,
+    src: &Path,
+    dist: Distribution,
+    patch: u16,
+    options: &BuildOptions,
+) -> anyhow::Result<BuildResult> {
+    // A data-only build doesn't package a tarball, so there's nothing to write out.
+    if options.data_only {
+        let (_, result) =
+            build_to_writer(files, dir, src, dist, patch, options, io::sink()).await?;
+        return Ok(result);
+    }
+
+    let output = dir.join(format!(
+        "{}-{}.{}",
+        object_name(dist, patch),
+        Uuid::new_v4(),
+        options.compression.extension()
+    ));
+    let file = File::create(&output)?;
+    let (file, mut result) =
+        match build_to_writer(files, dir, src, dist, patch, options, file).await {
+            Ok(built) => built,
+            Err(e) => {
+                let _ = fs::remove_file(&output);
+                return Err(e);
+            }
+        };
+    file.sync_all()?;
+    result.path = output;
+    Ok(result)
+}
+
+/// Builds a client, and writes it as a compressed tarball to a writer. The writer is handed back
+/// once the tarball is finished, so that in-memory or streamed destinations can be completed by
+/// the caller. For a data-only build, nothing is written.
+///
+/// # Arguments
+/// * `conn`    - The database connection.
+/// * `dir`     - The directory to build the client in.
+/// * `src`     - The root of the archive to read the client files from.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+/// * `options` - The build options.
+/// * `writer`  - The destination of the tarball.
+pub async fn build_client_to_writer<W: Write>(
+    conn: &Connection,
+    dir: &Path,
+    src: &Path,
+    dist: Distribution,
+    patch: u16,
+    options: &BuildOptions,
+    writer: W,
+) -> anyhow::Result<(W, BuildResult)> {
+    build_to_writer(
+        FileSource::Database(conn),
+        dir,
+        src,
+        dist,
+        patch,
+        options,
+        writer,
+    )
+    .await
+}
+
+/// Builds a client from a source of files, and writes it as a compressed tarball to a writer.
+///
+/// # Arguments
+/// * `files`   - The source of the client files.
+/// * `dir`     - The directory to build the client in.
+/// * `src`     - The root of the archive to read the client files from.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+/// * `options` - The build options.
+/// * `writer`  - The destination of the tarball.
+async fn build_to_writer<W: Write>(
+    files: FileSource<'_>,
+    dir: &Path,
+    src: &Path,
+    dist: Distribution,
+    patch: u16,
+    options: &BuildOptions,
+    writer: W,
+) -> anyhow::Result<(W, BuildResult)> {
+    if let Some(base) = options.base.filter(|&base| base >= patch) {
+        return Err(anyhow!(
+            "base patch {} must be earlier than patch {}",
+            base,
+            patch
+        ));
+    }
+
+    let mut timer = PhaseTimer::default();
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()?;
 
     // Retrieve the relevant files and populate the directory.
     // TODO: This really shouldn't even be a step (for the `data` directory). We should be able
     // to just skip this entirely and serialize directly to the data.saf file. That can be an optimisation
     // for the future, however.
-    let collected_files = collect_dist_files(conn, dist, patch).await?;
-    populate_client_directory(&collected_files, src, &dest, dist, patch).await?;
+    options.report(BuildStage::Collecting, 0, 0);
+    let mut collected_files = timer
+        .time_async("collect", files.collect(dist, patch))
+        .await?;
+    if let Some(base) = options.base {
+        let base_files = timer
+            .time_async("collect", files.collect(dist, base))
+            .await?;
+        collected_files = changed_files(base_files, collected_files);
+        if collected_files.is_empty() {
+            return Err(anyhow!(
+                "patch {} has no changes from patch {}",
+                patch,
+                base
+            ));
+        }
+        tracing::info!(
+            base,
+            changed = collected_files.len(),
+            "building incremental update"
+        );
+    }
+    if !options.exclude.is_empty() {
+        collected_files = exclude_files(collected_files, &options.exclude)?;
+        if collected_files.is_empty() {
+            return Err(anyhow!("every file of patch {} was excluded", patch));
+        }
+    }
+
+    let collected = collected_files.len() as u64;
+    options.report(BuildStage::Collecting, collected, collected);
+
+    // Check the archive has every source file before doing any work, so that gaps are reported
+    // all at once rather than failing partway through the build.
+    timer.time("preflight", || {
+        pool.install(|| preflight_check(&collected_files, src, options.strict))
+    })?;
+
+    // The temporary directory is removed if the build fails or is cancelled.
+    let temp_dir = create_temp_dir(dir, dist, patch)?;
+    let dest = temp_dir.path.clone();
+
+    // Stops the build if it's been cancelled.
+    let check_cancelled = || -> anyhow::Result<()> {
+        if options.is_cancelled() {
+            tracing::warn!(?dest, "build cancelled; removing temporary directory");
+            return Err(anyhow!("build of {} cancelled", object_name(dist, patch)));
+        }
+        Ok(())
+    };
+
+    // Look for game data built from the same files by an earlier build. If it's cached, only the
+    // loose files need to be populated.
+    let archive_name = if options.base.is_some() {
+        "update"
+    } else {
+        "data"
+    };
+    let cache_entry = options
+        .data_cache
+        .as_ref()
+        .map(|cache| cache.join(cache::key(&collected_files, archive_name)));
+    let cached = cache_entry.as_ref().is_some_and(|entry| entry.is_dir());
+    let loose_files;
+    let populated_files = if cached {
+        loose_files = collected_files
+            .iter()
+            .filter(|f| !cache::is_data_file(f))
+            .cloned()
+            .collect::<Vec<_>>();
+        &loose_files
+    } else {
+        &collected_files
+    };
+
+    check_cancelled()?;
+    let mut manifest = timer.time("populate", || {
+        pool.install(|| {
+            populate_client_directory(populated_files, src, &dest, dist, patch, options)
+        })
+    })?;
+    check_cancelled()?;
 
     // Get the most recent timestamp
     let most_recent_timestamp = collected_files.iter().map(|f| f.epoch).max().unwrap();
+    let uncompressed_size = collected_files
+        .iter()
+        .map(|f| f.uncompressed_size as u64)
+        .sum();
 
-    // Create are gzipped tarball for the file data.
-    let tar_gz = dest.join("game.tar.gz");
-    let tar_gz_file = File::create(&tar_gz)?;
-    let gzip = GzEncoder::new(tar_gz_file, Compression::fast());
-    let mut tar = Builder::new(gzip);
-
-    // Create the archive files.
-    let fs_header_path = dest.join("data.sah");
+    // Create the archive files. An incremental update may not change any game data, in which case
+    // the archive is empty.
+    let fs_header_path = dest.join(format!("{}.sah", archive_name));
     let data_path = dest.join("data");
-    let mut fs_header_file = File::create(&fs_header_path)?;
+    fs::create_dir_all(&data_path)?;
+    let data_file_path = dest.join(format!("{}.saf", archive_name));
 
-    let total_uncompressed_size: usize = collected_files
-        .par_iter()
-        .map(|f| f.uncompressed_size as usize)
-        .sum();
+    // Build the data file, either in memory or streamed to a file in the build directory so that
+    // memory use stays bounded for a full client.
+    let data_file = timer.time("build_saf", || -> anyhow::Result<DataFile> {
+        if let (true, Some(entry)) = (cached, &cache_entry) {
+            manifest.extend(cache::restore(entry, &fs_header_path, &data_file_path)?);
+            return Ok(DataFile::Disk(data_file_path.clone()));
+        }
+
+        let mut fs_header_file = File::create(&fs_header_path)?;
+        let fs = libclient::fs::Filesystem::from_path(&data_path)?;
+        if options.data_in_memory {
+            let total_uncompressed_size: usize = collected_files
+                .par_iter()
+                .map(|f| f.uncompressed_size as usize)
+                .sum();
+            let mut data_buf: Vec<u8> = Vec::with_capacity(total_uncompressed_size);
+            fs.build_with_destination(&mut fs_header_file, &mut data_buf)?;
+            Ok(DataFile::Memory(data_buf))
+        } else {
+            let mut data_writer = BufWriter::new(File::create(&data_file_path)?);
+            fs.build_with_destination(&mut fs_header_file, &mut data_writer)?;
+            data_writer.flush()?;
+            Ok(DataFile::Disk(data_file_path.clone()))
+        }
+    })?;
+    check_cancelled()?;
+    let data_buf_peak = match &data_file {
+        DataFile::Memory(data_buf) => {
+            tracing::info!(
+                data_buf_peak = data_buf.capacity(),
+                data_len = data_buf.len(),
+                "built data file in memory"
+            );
+            data_buf.capacity()
+        }
+        DataFile::Disk(path) => {
+            tracing::info!(?path, "built data file on disk");
+            0
+        }
+    };
+    // Cached game data was verified when it was built (if requested), and there's no populated
+    // data directory to compare it against.
+    if options.verify && !cached {
+        timer.time("verify", || {
+            verify::verify_round_trip(
+                &fs_header_path,
+                &data_file,
+                &data_path,
+                &dir.join(format!(
+                    "{}-verify",
+                    dest.file_name().unwrap().to_string_lossy()
+                )),
+            )
+        })?;
+    }
+    check_cancelled()?;
+
+    // Caching is best-effort, so a failure to store the game data doesn't fail the build.
+    if let (false, Some(entry)) = (cached, &cache_entry) {
+        let data_manifest = manifest
+            .iter()
+            .filter(|(path, _)| path.starts_with("data/"))
+            .map(|(path, entry)| (path.clone(), entry.clone()))
+            .collect();
+        if let Err(e) = cache::store(entry, &fs_header_path, &data_file, &data_manifest) {
+            tracing::warn!(?entry, "failed to cache game data: {:#}", e);
+        }
+    }
+
+    // If only the game data was requested, write out the data file alongside the header and stop
+    // before the client config is written or anything is packaged.
+    if options.data_only {
+        if let DataFile::Memory(data_buf) = &data_file {
+            fs::write(&data_file_path, data_buf)?;
+        }
+        tracing::info!(?data_path, "deleting data path to reclaim disk space...");
+        fs::remove_dir_all(&data_path)?;
+        return Ok((
+            writer,
+            BuildResult {
+                path: temp_dir.keep(),
+                phase_timings: timer.timings,
+                data_buf_peak,
+                misc_bytes_read: 0,
+                patch,
+                file_count: collected_files.len(),
+                uncompressed_size,
+                mtime: most_recent_timestamp,
+            },
+        ));
+    }
+
+    // Create a compressed tarball for the file data.
+    let encoder = Encoder::new(options.compression, options.compression_level, writer)?;
+    let mut tar = Builder::new(encoder);
+
+    timer.time("compress", || -> anyhow::Result<()> {
+        let name = format!("{}.saf", archive_name);
+        match data_file {
+            DataFile::Memory(data_buf) => {
+                let len = data_buf.len();
+                let reader = ProgressReader::new(data_buf.as_slice(), options, len as u64);
+                compress_file(&mut tar, &name, reader, len, most_recent_timestamp)
+            }
+            DataFile::Disk(path) => {
+                let file = File::open(&path)?;
+                let len = file.metadata()?.len() as usize;
+                let reader = ProgressReader::new(file, options, len as u64);
+                compress_file(&mut tar, &name, reader, len, most_recent_timestamp)?;
 
-    // Build the data file in memory, and then copy it to the file stream.
-    let mut data_buf: Vec<u8> = Vec::with_capacity(total_uncompressed_size);
-    let fs = libclient::fs::Filesystem::from_path(&data_path)?;
-    fs.build_with_destination(&mut fs_header_file, &mut data_buf)?;
-    compress_file(
-        &mut tar,
-        "data.saf",
-        &data_buf,
-        data_buf.len(),
-        most_recent_timestamp,
-    )?;
+                // Remove the data file, so that it isn't added again with the misc files.
+                fs::remove_file(&path)?;
+                Ok(())
+            }
+        }
+    })?;
+    check_cancelled()?;
 
     // Delete the data directory.
     tracing::info!(?data_path, "deleting data path to reclaim disk space...");
     fs::remove_dir_all(&data_path)?;
 
-    // Write the config files.
-    let gsconfig = GSCONFIG_TEMPLATE.replace(
-        "{address}",
-        &address.unwrap_or_else(|| "127.0.0.1".to_string()),
-    );
-    let version = VERSION_TEMPLATE.replace("{patch}", &patch.to_string());
-    fs::write(dest.join("gsconfig.cfg"), &gsconfig)?;
-    fs::write(dest.join("version.ini"), &version)?;
+    timer.time("config", || -> anyhow::Result<()> {
+        // Write the config files.
+        let address = options.address.as_deref().unwrap_or("127.0.0.1");
+        let mut gsconfig_values = BTreeMap::from([
+            ("address".to_string(), address.to_string()),
+            ("port".to_string(), DEFAULT_SERVER_PORT.to_string()),
+        ]);
+        gsconfig_values.extend(options.gsconfig_values.clone());
+        let gsconfig = render_template(
+            options
+                .gsconfig_template
+                .as_deref()
+                .unwrap_or(GSCONFIG_TEMPLATE),
+            &gsconfig_values,
+        )?;
+        let mut version_values = BTreeMap::from([("patch".to_string(), patch.to_string())]);
+        version_values.extend(options.version_values.clone());
+        let version = render_template(
+            options
+                .version_template
+                .as_deref()
+                .unwrap_or(VERSION_TEMPLATE),
+            &version_values,
+        )?;
+        fs::write(dest.join("gsconfig.cfg"), &gsconfig)?;
+        fs::write(dest.join("version.ini"), &version)?;
+
+        // Read the config.ini file. An incremental update only has it if it changed.
+        let config_path = dest.join("config.ini");
+        if config_path.is_file() {
+            write_client_config(&config_path, options)?;
+        }
+
+        // The loose files were rewritten above, so the manifest is written last, from the final
+        // contents of every loose file it lists and of the generated config files.
+        let mut loose_files = manifest
+            .keys()
+            .filter(|path| !path.contains('/'))
+            .cloned()
+            .collect::<Vec<_>>();
+        loose_files.extend(["gsconfig.cfg".to_string(), "version.ini".to_string()]);
+        for name in loose_files {
+            let data = match fs::read(dest.join(&name)) {
+                Ok(data) => data,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let entry = ManifestEntry {
+                size: data.len() as u64,
+                sha256: format!("{:x}", Sha256::digest(&data)),
+            };
+            manifest.insert(name, entry);
+        }
+        fs::write(
+            dest.join("manifest.json"),
+            serde_json::to_vec_pretty(&manifest)?,
+        )?;
+        Ok(())
+    })?;
+
+    // Collect all of the files in the root destination directory, and add them to the archive.
+    // They're sorted by name, as `read_dir` order depends on the filesystem, and two builds of the
+    // same patch should produce identical tarballs.
+    tracing::info!("adding misc files to archive...");
+    let mut misc_bytes_read = 0;
+    timer.time("compress", || -> anyhow::Result<()> {
+        let mut misc_files = fs::read_dir(&dest)?
+            .filter_map(Result::ok)
+            .map(|e| e.path())
+            .filter(|e| e.is_file())
+            .collect::<Vec<_>>();
+        misc_files.sort();
+        let total = misc_files.len() as u64;
+        for (idx, path) in misc_files.into_iter().enumerate() {
+            let filename = path
+                .file_name()
+                .and_then(|name| name.to_str())
+                .with_context(|| format!("invalid misc file name {:?}", path))?;
+            tracing::info!(filename, "appending file");
+
+            // Read the file data and write it to the archive
+            let buf =
+                fs::read(&path).with_context(|| format!("failed to read misc file {:?}", path))?;
+            misc_bytes_read += buf.len() as u64;
+            compress_file(
+                &mut tar,
+                filename,
+                buf.as_slice(),
+                buf.len(),
+                most_recent_timestamp,
+            )
+            .with_context(|| format!("failed to add {} to archive", filename))?;
+            options.report(BuildStage::AppendingFiles, idx as u64 + 1, total);
+        }
+        Ok(())
+    })?;
+    tracing::info!(misc_bytes_read, "added misc files to archive");
+    let writer = tar.into_inner()?.finish()?;
 
-    // Read the config.ini file;
-    let config_path = dest.join("config.ini");
-    let mut config = Ini::load_from_file(&config_path)?;
+    if options.keep_temp {
+        tracing::info!(?dest, "keeping temporary build directory");
+        temp_dir.keep();
+    } else {
+        temp_dir.remove()?;
+    }
+
+    Ok((
+        writer,
+        BuildResult {
+            path: dest,
+            phase_timings: timer.timings,
+            data_buf_peak,
+            misc_bytes_read,
+            patch,
+            file_count: collected_files.len(),
+            uncompressed_size,
+            mtime: most_recent_timestamp,
+        },
+    ))
+}
+
+/// Applies the client's defaults and any overrides to its `config.ini`.
+///
+/// # Arguments
+/// * `config_path` - The path to the `config.ini` file.
+/// * `options`     - The build options.
+fn write_client_config(config_path: &Path, options: &BuildOptions) -> anyhow::Result<()> {
+    let mut config = Ini::load_from_file(config_path)?;
 
     // Set the user id, and TEST_IP=ENGLISH (this forces international clients to use gsconfig ip)
     config
@@ -119,31 +790,224 @@ pub async fn build_client<'a>(
         .with_section(Some("VIDEO"))
         .set("FULLSCREEN", "FALSE");
 
-    config.write_to_file(&config_path)?;
+    // Apply any overrides on top of the defaults.
+    for (section, values) in &options.config_overrides {
+        for (key, value) in values {
+            config
+                .with_section(Some(section.as_str()))
+                .set(key.as_str(), value.as_str());
+        }
+    }
 
-    // Collect all of the files in the root destination directory, and add them to the archive.
-    tracing::info!("adding misc files to archive...");
-    fs::read_dir(&dest)?
-        .filter_map(Result::ok)
-        .map(|e| e.path())
-        .filter(|e| e.is_file() && e.to_str().unwrap() != tar_gz.to_str().unwrap())
-        .for_each(|path| {
-            let filename = path.file_name().expect("no file").to_str().unwrap();
-            tracing::info!(filename, "appending file");
+    config.write_to_file(config_path)?;
+    Ok(())
+}
 
-            // Read the file data and write it to the archive
-            let buf = fs::read(&path).expect("failed to read file data");
-            compress_file(&mut tar, filename, &buf, buf.len(), most_recent_timestamp)
-                .expect("failed to add file to archive");
-        });
-    tar.finish()?;
-    Ok(tar_gz)
+/// Builds clients for several distributions and patches, sharing one database connection. Each
+/// patch is normalized first, as with `normalize_patch`, and the files of each distribution are
+/// read from the database once and shared between its builds. The clients are built one at a
+/// time, as a single build already uses every thread in its pool and can hold a lot of memory. A
+/// failure to build one target is logged and reported in its slot of the result, and doesn't stop
+/// the remaining targets from building.
+///
+/// # Arguments
+/// * `conn`    - The database connection.
+/// * `dir`     - The directory to build the clients in.
+/// * `src`     - The root of the archive to read the client files from.
+/// * `targets` - The distributions and patches to build.
+/// * `options` - The options to build each client with.
+pub async fn build_clients(
+    conn: &Connection,
+    dir: &Path,
+    src: &Path,
+    targets: &[(Distribution, u16)],
+    options: &BuildOptions,
+) -> Vec<anyhow::Result<BuildResult>> {
+    let normalized = targets
+        .iter()
+        .map(|&(dist, patch)| normalize_patch(conn, dist, patch))
+        .collect::<Vec<_>>();
+
+    // Find the latest patch each distribution needs files from, including any incremental base.
+    let mut latest: Vec<(Distribution, u16)> = Vec::new();
+    for (&(dist, _), patch) in targets.iter().zip(&normalized) {
+        if let Ok(patch) = patch {
+            let patch = (*patch).max(options.base.unwrap_or(0));
+            match latest.iter_mut().find(|(d, _)| *d == dist) {
+                Some((_, latest)) => *latest = (*latest).max(patch),
+                None => latest.push((dist, patch)),
+            }
+        }
+    }
+
+    let mut versions: Vec<(Distribution, anyhow::Result<FileVersions>)> = Vec::new();
+    let mut results = Vec::with_capacity(targets.len());
+    for (&(dist, requested), normalized) in targets.iter().zip(normalized) {
+        let patch = match normalized {
+            Ok(patch) => patch,
+            Err(e) => {
+                tracing::error!(%dist, patch = requested, "{:#}", e);
+                results.push(Err(e));
+                continue;
+            }
+        };
+
+        // Read the distribution's files the first time one of its patches is built.
+        if !versions.iter().any(|(d, _)| *d == dist) {
+            let (_, latest) = latest.iter().find(|(d, _)| *d == dist).unwrap();
+            versions.push((dist, FileVersions::load(conn, dist, *latest)));
+        }
+        let (_, files) = versions.iter().find(|(d, _)| *d == dist).unwrap();
+
+        let result = match files {
+            Ok(files) => {
+                build_client_from(FileSource::Shared(files), dir, src, dist, patch, options).await
+            }
+            Err(e) => Err(anyhow!("failed to read the files of {}: {:#}", dist, e)),
+        };
+        let result =
+            result.with_context(|| format!("failed to build {}", object_name(dist, patch)));
+        if let Err(e) = &result {
+            tracing::error!(%dist, patch = requested, "{:#}", e);
+        }
+        results.push(result);
+    }
+    results
+}
+
+/// Checks that the source of every file exists in the archive, with the size recorded in the
+/// database. Every missing file (and, if `strict`, every mismatched size) is listed in the error.
+///
+/// # Arguments
+/// * `files`   - The files to check.
+/// * `src`     - The root of the archive.
+/// * `strict`  - Whether a mismatched size is an error, rather than a warning.
+fn preflight_check(files: &[ClientFile], src: &Path, strict: bool) -> anyhow::Result<()> {
+    let problems = files
+        .par_iter()
+        .filter_map(|file| match fs::metadata(src.join(&file.key)) {
+            Ok(metadata) if metadata.len() == file.uncompressed_size as u64 => None,
+            Ok(metadata) => Some((
+                &file.key,
+                format!(
+                    "expected {} bytes, found {}",
+                    file.uncompressed_size,
+                    metadata.len()
+                ),
+                false,
+            )),
+            Err(e) => Some((&file.key, e.to_string(), true)),
+        })
+        .collect::<Vec<_>>();
+
+    let mut errors = Vec::new();
+    for (key, problem, missing) in problems {
+        if missing || strict {
+            errors.push(format!("{}: {}", key, problem));
+        } else {
+            tracing::warn!(%key, %problem, "source file size differs from the database");
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "{} source files are missing or invalid:\n{}",
+            errors.len(),
+            errors.join("\n")
+        ));
+    }
+    Ok(())
+}
+
+/// Removes the files matching any of a set of glob patterns, logging how many files and bytes were
+/// left out.
+///
+/// # Arguments
+/// * `files`       - The files of the client.
+/// * `patterns`    - The glob patterns of the paths to exclude.
+fn exclude_files(files: Vec<ClientFile>, patterns: &[String]) -> anyhow::Result<Vec<ClientFile>> {
+    let patterns = patterns
+        .iter()
+        .map(|p| Pattern::new(p).with_context(|| format!("invalid exclude pattern `{}`", p)))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let match_options = MatchOptions {
+        case_sensitive: false,
+        ..MatchOptions::new()
+    };
+
+    let (excluded, kept): (Vec<_>, Vec<_>) = files.into_iter().partition(|file| {
+        patterns
+            .iter()
+            .any(|p| p.matches_with(&file.path, match_options))
+    });
+    let excluded_bytes: i64 = excluded.iter().map(|f| f.uncompressed_size).sum();
+    tracing::info!(
+        excluded = excluded.len(),
+        excluded_bytes,
+        "excluded files from client"
+    );
+    Ok(kept)
+}
+
+/// Gets the files of a patch that were added or changed since a base patch. A file is unchanged
+/// if the base has the same path with the same object key, as identical contents share a key.
+///
+/// # Arguments
+/// * `base_files`  - The files of the base patch.
+/// * `files`       - The files of the patch.
+fn changed_files(base_files: Vec<ClientFile>, files: Vec<ClientFile>) -> Vec<ClientFile> {
+    let base_keys = base_files
+        .into_iter()
+        .map(|f| (f.path, f.key))
+        .collect::<HashMap<_, _>>();
+    files
+        .into_iter()
+        .filter(|f| base_keys.get(&f.path) != Some(&f.key))
+        .collect()
+}
+
+/// Renders a template, by replacing each `{key}` placeholder with its value. Errors if the
+/// template contains a placeholder that no value was supplied for.
+///
+/// # Arguments
+/// * `template`    - The template.
+/// * `values`      - The value of each placeholder, keyed by its name.
+fn render_template(template: &str, values: &BTreeMap<String, String>) -> anyhow::Result<String> {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        rest = &rest[start..];
+
+        // Only `{...}` spans of identifier characters are placeholders; anything else is copied.
+        let name = rest[1..]
+            .find('}')
+            .map(|end| &rest[1..end + 1])
+            .filter(|name| {
+                !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+            });
+        match name {
+            Some(name) => {
+                let value = values.get(name).ok_or_else(|| {
+                    anyhow!("no value supplied for template placeholder `{{{}}}`", name)
+                })?;
+                rendered.push_str(value);
+                rest = &rest[name.len() + 2..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
 }
 
 fn compress_file<D: Write>(
     archive: &mut Builder<D>,
     name: &str,
-    data: &[u8],
+    data: impl Read,
     data_len: usize,
     timestamp: u64,
 ) -> anyhow::Result<()> {
@@ -158,6 +1022,25 @@ fn compress_file<D: Write>(
     Ok(())
 }
 
+/// Checks if a server address is a plausible IP address or hostname, for writing to
+/// `gsconfig.cfg`.
+///
+/// # Arguments
+/// * `address` - The server address.
+pub fn is_valid_address(address: &str) -> bool {
+    if address.parse::<IpAddr>().is_ok() {
+        return true;
+    }
+    address.len() <= 253
+        && address.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+        })
+}
+
 /// Get the formatted name of a distribution for a given patch number.
 ///
 /// # Arguments
@@ -167,20 +1050,63 @@ pub fn object_name(dist: Distribution, patch: u16) -> String {
     format!("shaiya-{}-ps{:04}", dist, patch)
 }
 
+/// A temporary build directory, which is removed when it's dropped unless it's kept. This cleans
+/// up after a build that fails partway through, which could otherwise leave gigabytes of client
+/// files behind.
+struct TempDir {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl TempDir {
+    /// Keeps the directory, rather than removing it when dropped, returning its path.
+    fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.path.clone()
+    }
+
+    /// Removes the directory now, reporting any failure to do so.
+    fn remove(mut self) -> io::Result<()> {
+        self.keep = true;
+        fs::remove_dir_all(&self.path)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if self.keep {
+            return;
+        }
+        match fs::remove_dir_all(&self.path) {
+            Ok(()) => tracing::info!(path = ?self.path, "removed temporary build directory"),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => tracing::warn!(
+                path = ?self.path,
+                error = %e,
+                "failed to remove temporary build directory"
+            ),
+        }
+    }
+}
+
 /// Creates a temporary directory, for storing the client files into. This will eventually
-/// be built into a zip archive and then deleted.
+/// be built into an archive and then deleted, unless the build options ask to keep it. If the
+/// build fails, it's deleted when the returned guard is dropped.
 ///
 /// # Arguments
 /// * `dist`    - The client distribution.
 /// * `patch`   - The requested patch number.
-fn create_temp_dir(dir: &Path, dist: Distribution, patch: u16) -> anyhow::Result<PathBuf> {
+fn create_temp_dir(dir: &Path, dist: Distribution, patch: u16) -> anyhow::Result<TempDir> {
     let dest = dir.join(format!("{}-{}", &object_name(dist, patch), Uuid::new_v4()));
     fs::create_dir_all(&dest)?;
     tracing::info!(?dest, "created temporary directory for client files");
-    Ok(dest)
+    Ok(TempDir {
+        path: dest,
+        keep: false,
+    })
 }
 
-async fn collect_dist_files(
+pub(crate) async fn collect_dist_files(
     conn: &Connection,
     dist: Distribution,
     patch: u16,
@@ -191,61 +1117,287 @@ async fn collect_dist_files(
     statement.bind::<i64>(2, patch as i64)?;
 
     while let State::Row = statement.next()? {
-        let path = statement.read::<String>(0)?;
-        let key = statement.read::<String>(1)?;
-        let uncompressed_size = statement.read::<i64>(2)?;
-        let date = statement.read::<String>(3)?;
+        files.push(read_client_file(&statement)?);
+    }
 
-        let date = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")?;
+    Ok(files)
+}
 
-        files.push(ClientFile {
-            path,
-            key,
-            uncompressed_size,
-            epoch: date.timestamp() as u64,
-        });
+/// Reads a client file from the current row of a statement, whose first columns are the path,
+/// key, uncompressed size, date and SHA-256 of the file.
+///
+/// # Arguments
+/// * `statement`   - The statement.
+fn read_client_file(statement: &Statement) -> anyhow::Result<ClientFile> {
+    let path = statement.read::<String>(0)?;
+    let key = statement.read::<String>(1)?;
+    let uncompressed_size = statement.read::<i64>(2)?;
+    let date = statement.read::<String>(3)?;
+    let sha256 = match statement.read::<Value>(4)? {
+        Value::String(sha256) => Some(sha256),
+        _ => None,
+    };
+
+    let date = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")?;
+
+    Ok(ClientFile {
+        path,
+        key,
+        uncompressed_size,
+        epoch: date.timestamp() as u64,
+        sha256,
+    })
+}
+
+/// Every version of a distribution's files up to a patch, read from the database once so that
+/// builds of several patches can share them.
+struct FileVersions {
+    /// The versions, as the patch that introduced them and the file, ordered by path and patch.
+    versions: Vec<(u16, ClientFile)>,
+}
+
+impl FileVersions {
+    /// Reads every version of a distribution's files, up to and including a patch.
+    ///
+    /// # Arguments
+    /// * `conn`    - The database connection.
+    /// * `dist`    - The client distribution.
+    /// * `patch`   - The latest patch to read.
+    fn load(conn: &Connection, dist: Distribution, patch: u16) -> anyhow::Result<Self> {
+        let mut versions = Vec::with_capacity(65535);
+        let mut statement = conn.prepare(include_str!("../queries/file_versions_for_dist.sql"))?;
+        statement.bind::<&str>(1, dist.into())?;
+        statement.bind::<i64>(2, patch as i64)?;
+
+        while let State::Row = statement.next()? {
+            let file = read_client_file(&statement)?;
+            let patch = statement.read::<i64>(5)? as u16;
+            versions.push((patch, file));
+        }
+        Ok(Self { versions })
     }
 
-    Ok(files)
+    /// Gets the files of a patch, which are the latest version of each file at or below it. This
+    /// matches what `collect_dist_files` would read from the database.
+    ///
+    /// # Arguments
+    /// * `patch`   - The patch.
+    fn at(&self, patch: u16) -> Vec<ClientFile> {
+        let mut files: Vec<ClientFile> = Vec::new();
+        for (_, file) in self.versions.iter().filter(|(p, _)| *p <= patch) {
+            match files.last_mut() {
+                Some(last) if last.path == file.path => *last = file.clone(),
+                _ => files.push(file.clone()),
+            }
+        }
+        files
+    }
 }
 
-/// Populates a client directory with the files for a specified path.
+/// Where a build reads the list of a patch's files from.
+#[derive(Clone, Copy)]
+enum FileSource<'a> {
+    /// Read from the database for each patch.
+    Database(&'a Connection),
+    /// Shared with the other builds of a distribution.
+    Shared(&'a FileVersions),
+}
+
+impl FileSource<'_> {
+    /// Collects the files of a patch.
+    ///
+    /// # Arguments
+    /// * `dist`    - The client distribution.
+    /// * `patch`   - The patch.
+    async fn collect(&self, dist: Distribution, patch: u16) -> anyhow::Result<Vec<ClientFile>> {
+        match self {
+            FileSource::Database(conn) => collect_dist_files(conn, dist, patch).await,
+            FileSource::Shared(versions) => Ok(versions.at(patch)),
+        }
+    }
+}
+
+/// Populates a client directory with the files for a specified patch, returning the size and
+/// SHA-256 of each file keyed by its path, for the client's `manifest.json`.
+///
+/// Each file is checked against the database as it's read: a mismatched checksum (if
+/// `verify_checksums` is set) or, if `strict`, a mismatched size fails the build. Every mismatch
+/// is listed in the error, rather than only the first.
 ///
 /// # Arguments
-/// * `conn`    - The database connection.
-/// * `s3       - The AWS s3 client.
+/// * `files`   - The files that make up the client.
+/// * `src`     - The archive directory to read the file data from.
 /// * `dest`    - The directory to write the files to.
 /// * `dist`    - The client distribution.
 /// * `patch`   - The requested patch.
-async fn populate_client_directory(
+/// * `options` - The build options.
+fn populate_client_directory(
     files: &[ClientFile],
     src: &Path,
     dest: &Path,
     dist: Distribution,
     patch: u16,
-) -> anyhow::Result<()> {
-    files
+    options: &BuildOptions,
+) -> anyhow::Result<BTreeMap<String, ManifestEntry>> {
+    let total = files.len() as u64;
+    let populated = AtomicU64::new(0);
+    let results = files
         .par_iter()
-        .map(|file| {
-            let ClientFile { path, key, .. } = &file;
-            let path = dest.join(&path);
-            if let Some(parent) = path.parent() {
-                fs::create_dir_all(parent)?;
+        .map(
+            |file| -> anyhow::Result<Result<(String, ManifestEntry), String>> {
+                let ClientFile {
+                    path: relative,
+                    key,
+                    sha256,
+                    uncompressed_size,
+                    ..
+                } = &file;
+                let path = dest.join(&relative);
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                let src_path = src.join(&key);
+                let data = fs::read(&src_path)?;
+
+                // The preflight check has already warned about a mismatched size, so it only needs
+                // reporting here if it's an error.
+                if options.strict && data.len() as i64 != *uncompressed_size {
+                    return Ok(Err(format!(
+                        "{}: expected {} bytes, read {}",
+                        key,
+                        uncompressed_size,
+                        data.len()
+                    )));
+                }
+
+                // Files imported before checksums were recorded have no hash, so can't be verified.
+                let actual = format!("{:x}", Sha256::digest(&data));
+                if let (true, Some(expected)) = (options.verify_checksums, sha256) {
+                    if !actual.eq_ignore_ascii_case(expected) {
+                        return Ok(Err(format!(
+                            "{}: checksum mismatch (expected {}, found {})",
+                            key, expected, actual
+                        )));
+                    }
+                }
+
+                let mut dst = fs::File::create(&path)?;
+                dst.write_all(&data)?;
+                tracing::trace!(?path, %key, %dist, patch, "wrote file");
+                let current = populated.fetch_add(1, Ordering::Relaxed) + 1;
+                options.report(BuildStage::Populating, current, total);
+                let entry = ManifestEntry {
+                    size: data.len() as u64,
+                    sha256: actual,
+                };
+                Ok(Ok((relative.clone(), entry)))
+            },
+        )
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let mut manifest = BTreeMap::new();
+    let mut problems = Vec::new();
+    for result in results {
+        match result {
+            Ok((path, entry)) => {
+                manifest.insert(path, entry);
             }
+            Err(problem) => problems.push(problem),
+        }
+    }
+    if !problems.is_empty() {
+        problems.sort();
+        return Err(anyhow!(
+            "{} source files don't match the database:\n{}",
+            problems.len(),
+            problems.join("\n")
+        ));
+    }
+    Ok(manifest)
+}
+
+/// Estimates the download size of a client, without building it. This is the total uncompressed
+/// size of the client's files scaled by `ESTIMATED_COMPRESSION_RATIO`, so it's only a ballpark
+/// figure: it ignores the tar headers and config files, and real ratios vary between patches.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+pub async fn estimated_size(
+    conn: &Connection,
+    dist: Distribution,
+    patch: u16,
+) -> anyhow::Result<u64> {
+    let uncompressed_size: u64 = collect_dist_files(conn, dist, patch)
+        .await?
+        .iter()
+        .map(|f| f.uncompressed_size as u64)
+        .sum();
+    Ok((uncompressed_size as f64 * ESTIMATED_COMPRESSION_RATIO) as u64)
+}
+
+/// Counts the files that make up a client, without building it or collecting its files. A complete
+/// client has thousands of files, so a count far below that means the archive has gaps for the
+/// dist and patch.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch.
+pub async fn file_count(
+    conn: &Connection,
+    dist: Distribution,
+    patch: u16,
+) -> anyhow::Result<usize> {
+    let mut statement = conn.prepare(include_str!("../queries/count_files_for_dist.sql"))?;
+    statement.bind::<&str>(1, dist.into())?;
+    statement.bind::<i64>(2, patch as i64)?;
+    statement.next()?;
+    Ok(statement.read::<i64>(0)? as usize)
+}
 
-            let src_path = src.join(&key);
-            let data = fs::read(&src_path)?;
+/// Why a patch couldn't be normalized, as the archive has nothing at or below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnavailablePatch {
+    /// The distribution has no archived patches at all.
+    NoPatches { dist: Distribution },
+    /// The patch is below the lowest patch archived for the distribution.
+    BelowLowest {
+        dist: Distribution,
+        patch: u16,
+        lowest: u16,
+        highest: u16,
+    },
+}
 
-            let mut dst = fs::File::create(&path)?;
-            dst.write_all(&data)?;
-            tracing::trace!(?path, %key, %dist, patch, "wrote file");
-            Ok(())
-        })
-        .collect::<anyhow::Result<()>>()
+impl std::fmt::Display for UnavailablePatch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UnavailablePatch::NoPatches { dist } => {
+                write!(f, "dist `{}` has no archived patches", dist)
+            }
+            UnavailablePatch::BelowLowest {
+                dist,
+                patch,
+                lowest,
+                highest,
+            } => write!(
+                f,
+                "patch {} is below the lowest available patch for dist `{}` (available: {} to {})",
+                patch, dist, lowest, highest
+            ),
+        }
+    }
 }
 
+impl std::error::Error for UnavailablePatch {}
+
 /// Normalizes a patch number for a specified distribution. If `patch` does not exist for a
-/// distribution, it gets the next lowest available patch number.
+/// distribution, it gets the next lowest available patch number. If there is no such patch, the
+/// error is an [`UnavailablePatch`], saying whether the distribution has no patches at all, or
+/// the range of patches it has; any other error is a failure to query the database.
 ///
 /// # Arguments
 /// * `conn`    - The connection to the database.
@@ -256,8 +1408,549 @@ pub fn normalize_patch(conn: &Connection, dist: Distribution, patch: u16) -> any
     statement.bind::<&str>(1, dist.into())?;
     statement.bind::<i64>(2, patch as i64)?;
 
-    if let State::Done = statement.next()? {
-        return Err(anyhow!("couldn't find patch for dist `{}`", dist));
+    // The query always returns a row, which is null if there's no patch at or below `patch`.
+    if let State::Row = statement.next()? {
+        if let Value::Integer(normalized) = statement.read::<Value>(0)? {
+            return Ok(normalized as u16);
+        }
+    }
+
+    let patches = list_patches(conn, dist)?;
+    let error = match (patches.first(), patches.last()) {
+        (Some(lowest), Some(highest)) => UnavailablePatch::BelowLowest {
+            dist,
+            patch,
+            lowest: *lowest,
+            highest: *highest,
+        },
+        _ => UnavailablePatch::NoPatches { dist },
+    };
+    Err(error.into())
+}
+
+/// Lists the patches of a distribution that have archived files, in ascending order.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+/// * `dist`    - The client distribution.
+pub fn list_patches(conn: &Connection, dist: Distribution) -> anyhow::Result<Vec<u16>> {
+    let mut patches = Vec::new();
+    let mut statement = conn.prepare(include_str!("../queries/list_patches.sql"))?;
+    statement.bind::<&str>(1, dist.into())?;
+
+    while let State::Row = statement.next()? {
+        patches.push(statement.read::<i64>(0)? as u16);
+    }
+    Ok(patches)
+}
+
+/// Gets the history of a file path for a distribution, as the ordered sequence of patches which
+/// introduced or changed it, along with the object key of that version and its date.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+/// * `dist`    - The client distribution.
+/// * `path`    - The path of the file, relative to the client root (i.e. `data/item/item.sdata`).
+pub fn file_history(
+    conn: &Connection,
+    dist: Distribution,
+    path: &str,
+) -> anyhow::Result<Vec<(u16, String, NaiveDateTime)>> {
+    let mut history = Vec::new();
+    let mut statement = conn.prepare(include_str!("../queries/file_history.sql"))?;
+    statement.bind::<&str>(1, dist.into())?;
+    statement.bind::<&str>(2, &path.to_lowercase())?;
+
+    while let State::Row = statement.next()? {
+        let patch = statement.read::<i64>(0)? as u16;
+        let key = statement.read::<String>(1)?;
+        let date = statement.read::<String>(2)?;
+        let date = NaiveDateTime::parse_from_str(&date, "%Y-%m-%d %H:%M:%S")?;
+        history.push((patch, key, date));
+    }
+    Ok(history)
+}
+
+/// Searches for files across every distribution and patch, by the start of their path (i.e.
+/// `data/item/` for every item file). Each match is a path and distribution, along with the first
+/// and last patches that introduced or changed it, ordered by path.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+/// * `prefix`  - The start of the path, relative to the client root.
+/// * `limit`   - The most matches to return.
+pub fn search_files(
+    conn: &Connection,
+    prefix: &str,
+    limit: usize,
+) -> anyhow::Result<Vec<(String, Distribution, u16, u16)>> {
+    let mut matches = Vec::new();
+    let mut statement = conn.prepare(include_str!("../queries/search_files.sql"))?;
+    statement.bind::<&str>(1, &prefix.to_lowercase())?;
+    statement.bind::<i64>(2, limit as i64)?;
+
+    while let State::Row = statement.next()? {
+        let path = statement.read::<String>(0)?;
+        let dist = statement.read::<String>(1)?;
+        let first = statement.read::<i64>(2)? as u16;
+        let last = statement.read::<i64>(3)? as u16;
+        match dist.parse::<Distribution>() {
+            Ok(dist) => matches.push((path, dist, first, last)),
+            Err(_) => tracing::warn!(%dist, "skipping unknown distribution in database"),
+        }
+    }
+    Ok(matches)
+}
+
+/// Gets the distributions that have archived files, along with the latest patch of each. A
+/// distribution with no files isn't included, as any build of it would fail.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+pub fn available_distributions(conn: &Connection) -> anyhow::Result<Vec<(Distribution, u16)>> {
+    let mut distributions = Vec::new();
+    let mut statement = conn.prepare(include_str!("../queries/available_distributions.sql"))?;
+
+    while let State::Row = statement.next()? {
+        let dist = statement.read::<String>(0)?;
+        let patch = statement.read::<i64>(1)? as u16;
+        match dist.parse::<Distribution>() {
+            Ok(dist) => distributions.push((dist, patch)),
+            Err(_) => tracing::warn!(%dist, "skipping unknown distribution in database"),
+        }
+    }
+    Ok(distributions)
+}
+
+/// Opens the archive database at a provided path, and migrates it to the latest schema version.
+/// A connection waits for others that are writing to the database (e.g. migrating it), rather
+/// than failing straight away.
+///
+/// # Arguments
+/// * `path`    - The database path.
+pub fn init_db(path: &Path) -> anyhow::Result<Connection> {
+    let mut conn = sqlite::open(path)?;
+    conn.set_busy_timeout(DB_BUSY_TIMEOUT_MS)?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+/// Rebuilds the indexes of the `files` table, and refreshes the statistics the query planner uses
+/// to choose between them. This should be run after a bulk import. Any missing indexes are created
+/// first, by migrating the database.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+pub fn rebuild_indexes(conn: &Connection) -> anyhow::Result<()> {
+    migrate(conn)?;
+    let start = Instant::now();
+    conn.execute(include_str!("../queries/rebuild_indexes.sql"))?;
+    tracing::info!(
+        elapsed_ms = start.elapsed().as_millis() as u64,
+        "rebuilt database indexes"
+    );
+    Ok(())
+}
+
+/// Applies any outstanding schema migrations to the database. The schema version is tracked
+/// with `PRAGMA user_version`, so this is a no-op for a database that is already up-to-date.
+///
+/// This is safe to run from several processes sharing a database at once (e.g. lambdas starting
+/// together): each migration holds the write lock while it checks the version and applies itself,
+/// so a migration another process has already applied is skipped rather than applied twice.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+pub fn migrate(conn: &Connection) -> anyhow::Result<()> {
+    while schema_version(conn)? < MIGRATIONS.len() {
+        conn.execute("BEGIN IMMEDIATE")?;
+        let version = match schema_version(conn) {
+            Ok(version) => version,
+            Err(e) => {
+                conn.execute("ROLLBACK")?;
+                return Err(e);
+            }
+        };
+        if version >= MIGRATIONS.len() {
+            conn.execute("COMMIT")?;
+            break;
+        }
+
+        let target = version + 1;
+        tracing::info!(version = target, "applying database migration");
+        let result = conn
+            .execute(MIGRATIONS[version])
+            .and_then(|_| conn.execute(format!("PRAGMA user_version = {}", target)));
+        match result {
+            Ok(_) => conn.execute("COMMIT")?,
+            Err(e) => {
+                conn.execute("ROLLBACK")?;
+                return Err(anyhow!("failed to apply migration {}: {}", target, e));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Gets the schema version of the database, which is the number of migrations applied to it.
+///
+/// # Arguments
+/// * `conn`    - The connection to the database.
+fn schema_version(conn: &Connection) -> anyhow::Result<usize> {
+    let mut statement = conn.prepare("PRAGMA user_version")?;
+    statement.next()?;
+    Ok(statement.read::<i64>(0)? as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an in-memory database at the latest schema version, containing the provided files.
+    ///
+    /// # Arguments
+    /// * `files`   - The files, as their distribution, patch, path and key.
+    fn fixture(files: &[(&str, u16, &str, &str)]) -> Connection {
+        let conn = sqlite::open(":memory:").unwrap();
+        migrate(&conn).unwrap();
+        for (idx, (dist, patch, path, key)) in files.iter().enumerate() {
+            conn.execute(format!(
+                "INSERT INTO filedata (id, checksum, uncompressed_size, key) VALUES ({}, {}, 4, '{}');
+                 INSERT INTO files (distribution, patch, path, date, fileid)
+                     VALUES ('{}', {}, '{}', '2010-01-01 00:00:00', {});",
+                idx + 1,
+                idx + 1,
+                key,
+                dist,
+                patch,
+                path,
+                idx + 1
+            ))
+            .unwrap();
+        }
+        conn
+    }
+
+    #[tokio::test]
+    async fn queries_run_against_a_migrated_database() {
+        let conn = fixture(&[
+            ("us", 1, "game.exe", "us/1/game.exe"),
+            ("us", 3, "game.exe", "us/3/game.exe"),
+            ("us", 3, "data/item/item.sdata", "us/3/item.sdata"),
+        ]);
+
+        // Migrating an up-to-date database should be a no-op.
+        migrate(&conn).unwrap();
+        assert_eq!(schema_version(&conn).unwrap(), MIGRATIONS.len());
+
+        assert_eq!(normalize_patch(&conn, Distribution::Us, 2).unwrap(), 1);
+        assert_eq!(normalize_patch(&conn, Distribution::Us, 10).unwrap(), 3);
+
+        let files = collect_dist_files(&conn, Distribution::Us, 2)
+            .await
+            .unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].key, "us/1/game.exe");
+        assert_eq!(files[0].sha256, None);
+
+        let files = collect_dist_files(&conn, Distribution::Us, 3)
+            .await
+            .unwrap();
+        let keys = files
+            .iter()
+            .map(|file| file.key.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["us/3/item.sdata", "us/3/game.exe"]);
+    }
+
+    #[tokio::test]
+    async fn shared_file_versions_match_collected_files() {
+        let conn = fixture(&[
+            ("us", 1, "game.exe", "us/1/game.exe"),
+            ("us", 1, "config.ini", "us/1/config.ini"),
+            ("us", 3, "game.exe", "us/3/game.exe"),
+            ("us", 4, "data/item/item.sdata", "us/4/item.sdata"),
+            ("de", 2, "game.exe", "de/2/game.exe"),
+        ]);
+        let versions = FileVersions::load(&conn, Distribution::Us, 4).unwrap();
+
+        for patch in 0..=4 {
+            let shared = versions.at(patch);
+            let collected = collect_dist_files(&conn, Distribution::Us, patch)
+                .await
+                .unwrap();
+            let keys = |files: &[ClientFile]| {
+                files
+                    .iter()
+                    .map(|file| (file.path.clone(), file.key.clone()))
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(keys(&shared), keys(&collected), "patch {}", patch);
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_the_files_that_would_be_collected() {
+        let conn = fixture(&[
+            ("us", 1, "game.exe", "us/1/game.exe"),
+            ("us", 1, "config.ini", "us/1/config.ini"),
+            ("us", 3, "game.exe", "us/3/game.exe"),
+            ("us", 4, "data/item/item.sdata", "us/4/item.sdata"),
+            ("de", 2, "game.exe", "de/2/game.exe"),
+        ]);
+
+        for patch in 0..=4 {
+            let collected = collect_dist_files(&conn, Distribution::Us, patch)
+                .await
+                .unwrap();
+            let count = file_count(&conn, Distribution::Us, patch).await.unwrap();
+            assert_eq!(count, collected.len(), "patch {}", patch);
+        }
+    }
+
+    #[test]
+    fn lists_available_distributions() {
+        let conn = fixture(&[
+            ("us", 100, "game.exe", "us/100/game.exe"),
+            ("us", 430, "game.exe", "us/430/game.exe"),
+            ("de", 12, "game.exe", "de/12/game.exe"),
+            ("cn", 5, "game.exe", "cn/5/game.exe"),
+        ]);
+
+        // `cn` isn't a distribution the builder supports, so it's skipped.
+        let distributions = available_distributions(&conn).unwrap();
+        assert_eq!(
+            distributions,
+            [(Distribution::De, 12), (Distribution::Us, 430)]
+        );
+    }
+
+    #[test]
+    fn searches_files_by_path_prefix() {
+        let conn = fixture(&[
+            ("us", 1, "data/item/item.sdata", "us/1/item.sdata"),
+            ("us", 3, "data/item/item.sdata", "us/3/item.sdata"),
+            ("us", 3, "data/item/itemname.sdata", "us/3/itemname.sdata"),
+            ("de", 2, "data/item/item.sdata", "de/2/item.sdata"),
+            ("us", 1, "data/itemx/a.tga", "us/1/a.tga"),
+            ("us", 1, "game.exe", "us/1/game.exe"),
+        ]);
+
+        let matches = search_files(&conn, "Data/Item/", 10).unwrap();
+        assert_eq!(
+            matches,
+            [
+                ("data/item/item.sdata".to_string(), Distribution::De, 2, 2),
+                ("data/item/item.sdata".to_string(), Distribution::Us, 1, 3),
+                (
+                    "data/item/itemname.sdata".to_string(),
+                    Distribution::Us,
+                    3,
+                    3
+                ),
+            ]
+        );
+        assert_eq!(search_files(&conn, "data/item/", 1).unwrap().len(), 1);
+        assert!(search_files(&conn, "data/map/", 10).unwrap().is_empty());
+    }
+
+    #[test]
+    fn lists_no_distributions_for_empty_database() {
+        let conn = fixture(&[]);
+        assert!(available_distributions(&conn).unwrap().is_empty());
+    }
+
+    /// Creates a directory of archive files for a test, from their keys and contents.
+    ///
+    /// # Arguments
+    /// * `files`   - The keys and contents of the files.
+    fn archive_fixture(files: &[(&str, &str)]) -> PathBuf {
+        let src = std::env::temp_dir().join(format!("clientbuilder-test-{}", Uuid::new_v4()));
+        for (key, contents) in files {
+            let path = src.join(key);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        src
+    }
+
+    #[tokio::test]
+    async fn builds_are_reproducible() {
+        // The fixture records every file as 4 bytes.
+        let conn = fixture(&[
+            ("us", 1, "game.exe", "us/1/game.exe"),
+            ("us", 1, "config.ini", "us/1/config.ini"),
+            ("us", 1, "data/item/item.sdata", "us/1/item.sdata"),
+            ("us", 1, "data/interface/a.tga", "us/1/a.tga"),
+        ]);
+        let src = archive_fixture(&[
+            ("us/1/game.exe", "exe!"),
+            ("us/1/config.ini", "[A]\n"),
+            ("us/1/item.sdata", "item"),
+            ("us/1/a.tga", "tga!"),
+        ]);
+        let dir = src.join("build");
+        fs::create_dir_all(&dir).unwrap();
+
+        for compression in ArchiveCompression::ALL {
+            let options = BuildOptions {
+                compression,
+                ..Default::default()
+            };
+            let mut builds = Vec::new();
+            for _ in 0..2 {
+                let (tarball, _) = build_client_to_writer(
+                    &conn,
+                    &dir,
+                    &src,
+                    Distribution::Us,
+                    1,
+                    &options,
+                    Vec::new(),
+                )
+                .await
+                .unwrap();
+                builds.push(tarball);
+            }
+            assert!(!builds[0].is_empty());
+            assert!(builds[0] == builds[1], "{} builds differ", compression);
+        }
+
+        // Every temporary build directory should have been removed.
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 0);
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[tokio::test]
+    async fn builds_decode_with_each_compression() {
+        // The fixture records every file as 4 bytes.
+        let conn = fixture(&[
+            ("us", 1, "game.exe", "us/1/game.exe"),
+            ("us", 1, "config.ini", "us/1/config.ini"),
+            ("us", 1, "data/item/item.sdata", "us/1/item.sdata"),
+        ]);
+        let src = archive_fixture(&[
+            ("us/1/game.exe", "exe!"),
+            ("us/1/config.ini", "[A]\n"),
+            ("us/1/item.sdata", "item"),
+        ]);
+        let dir = src.join("build");
+        fs::create_dir_all(&dir).unwrap();
+
+        for compression in ArchiveCompression::ALL {
+            let options = BuildOptions {
+                compression,
+                ..Default::default()
+            };
+            let (tarball, _) = build_client_to_writer(
+                &conn,
+                &dir,
+                &src,
+                Distribution::Us,
+                1,
+                &options,
+                Vec::new(),
+            )
+            .await
+            .unwrap();
+
+            let decoder = compression.decoder(tarball.as_slice()).unwrap();
+            let mut archive = tar::Archive::new(decoder);
+            let mut entries = archive
+                .entries()
+                .unwrap()
+                .map(|entry| entry.unwrap().path().unwrap().to_string_lossy().to_string())
+                .collect::<Vec<_>>();
+            entries.sort();
+            assert_eq!(
+                entries,
+                [
+                    "config.ini",
+                    "data.saf",
+                    "data.sah",
+                    "game.exe",
+                    "gsconfig.cfg",
+                    "manifest.json",
+                    "version.ini",
+                ],
+                "{} build",
+                compression
+            );
+        }
+        fs::remove_dir_all(&src).unwrap();
+    }
+
+    #[test]
+    fn rejects_patch_for_dist_without_patches() {
+        let conn = fixture(&[("us", 100, "game.exe", "us/100/game.exe")]);
+        let error = normalize_patch(&conn, Distribution::De, 100).unwrap_err();
+        assert_eq!(error.to_string(), "dist `de` has no archived patches");
+        assert_eq!(
+            error.downcast_ref::<UnavailablePatch>(),
+            Some(&UnavailablePatch::NoPatches {
+                dist: Distribution::De
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_patch_below_lowest_patch() {
+        let conn = fixture(&[
+            ("us", 100, "game.exe", "us/100/game.exe"),
+            ("us", 430, "game.exe", "us/430/game.exe"),
+        ]);
+        let error = normalize_patch(&conn, Distribution::Us, 50).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "patch 50 is below the lowest available patch for dist `us` (available: 100 to 430)"
+        );
+    }
+
+    /// Creates the values of a template from pairs of names and values.
+    ///
+    /// # Arguments
+    /// * `values`  - The names and values.
+    fn template_values(values: &[(&str, &str)]) -> BTreeMap<String, String> {
+        values
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn renders_custom_template() {
+        let values = template_values(&[("address", "10.0.0.1"), ("server_name", "Test")]);
+        let rendered = render_template("[framework]\nIP={address}\nName={server_name}\n", &values);
+        assert_eq!(rendered.unwrap(), "[framework]\nIP=10.0.0.1\nName=Test\n");
+    }
+
+    #[test]
+    fn copies_stray_and_nested_braces() {
+        let values = template_values(&[("address", "10.0.0.1")]);
+        let render = |template: &str| render_template(template, &values).unwrap();
+        assert_eq!(render("a { b {address}"), "a { b 10.0.0.1");
+        assert_eq!(render("trailing {"), "trailing {");
+        assert_eq!(render("{}"), "{}");
+        assert_eq!(render("{not a placeholder}"), "{not a placeholder}");
+        assert_eq!(render("{{address}}"), "{10.0.0.1}");
+    }
+
+    #[test]
+    fn rejects_unknown_placeholder() {
+        let values = template_values(&[("address", "10.0.0.1")]);
+        let error = render_template("IP={address}\nPort={port}", &values).unwrap_err();
+        assert!(error.to_string().contains("`{port}`"), "{}", error);
+    }
+
+    #[test]
+    fn renders_gsconfig_port() {
+        let port = DEFAULT_SERVER_PORT.to_string();
+        let values = template_values(&[("address", "10.0.0.1"), ("port", &port)]);
+        let rendered = render_template(GSCONFIG_TEMPLATE, &values).unwrap();
+        assert!(
+            rendered.contains("IP=10.0.0.1\nPORT=30800\n"),
+            "{}",
+            rendered
+        );
     }
-    Ok(statement.read::<i64>(0)? as u16)
 }
@@ -0,0 +1,246 @@
+use crate::{collect_dist_files, ArchiveCompression, DataFile, Distribution};
+use anyhow::anyhow;
+use sqlite::Connection;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::File;
+use std::path::Path;
+use tar::Archive;
+use uuid::Uuid;
+
+/// The files that are generated by `build_client`, and as such aren't present in the database.
+const GENERATED_FILES: &[&str] = &[
+    "data.sah",
+    "data.saf",
+    "gsconfig.cfg",
+    "manifest.json",
+    "version.ini",
+];
+
+/// The files that `build_client` rewrites, and may legitimately differ in size from the database.
+const REWRITTEN_FILES: &[&str] = &["config.ini", "gsconfig.cfg", "version.ini"];
+
+/// A difference between the contents of a built client, and the files the database says it
+/// should contain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Discrepancy {
+    /// A file is in the database, but not in the built client.
+    Missing { path: String },
+    /// A file is in the built client, but not in the database.
+    Extra { path: String },
+    /// A file is in both, but the sizes differ.
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl Display for Discrepancy {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Discrepancy::Missing { path } => write!(f, "missing: {}", path),
+            Discrepancy::Extra { path } => write!(f, "extra: {}", path),
+            Discrepancy::SizeMismatch {
+                path,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "size mismatch: {} (expected {} bytes, found {} bytes)",
+                path, expected, actual
+            ),
+        }
+    }
+}
+
+/// The result of verifying a built client.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    /// The number of files found in the built client.
+    pub file_count: usize,
+    /// The differences between the built client and the database.
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl VerifyReport {
+    /// Whether the built client matched the database.
+    pub fn passed(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+/// Verifies that a built client tarball contains every file the database says it should, with
+/// the expected sizes.
+///
+/// # Arguments
+/// * `conn`    - The database connection.
+/// * `dir`     - The directory to unpack the client into, while verifying.
+/// * `tarball` - The path to the built client tarball (i.e. `game.tar.gz`).
+/// * `dist`    - The client distribution.
+/// * `patch`   - The patch the client was built for.
+pub async fn verify_client(
+    conn: &Connection,
+    dir: &Path,
+    tarball: &Path,
+    dist: Distribution,
+    patch: u16,
+) -> anyhow::Result<VerifyReport> {
+    let scratch = dir.join(format!("verify-{}", Uuid::new_v4()));
+    fs::create_dir_all(&scratch)?;
+    tracing::info!(?scratch, ?tarball, "unpacking client for verification");
+
+    let result = verify_unpacked(conn, &scratch, tarball, dist, patch).await;
+    fs::remove_dir_all(&scratch)?;
+    result
+}
+
+async fn verify_unpacked(
+    conn: &Connection,
+    scratch: &Path,
+    tarball: &Path,
+    dist: Distribution,
+    patch: u16,
+) -> anyhow::Result<VerifyReport> {
+    // Unpack the tarball, and extract the archive filesystem alongside the loose files.
+    let compression = ArchiveCompression::from_path(tarball)
+        .ok_or_else(|| anyhow!("unrecognised client archive extension: {:?}", tarball))?;
+    let mut archive = Archive::new(compression.decoder(File::open(tarball)?)?);
+    archive.unpack(scratch)?;
+
+    let fs = libclient::fs::Filesystem::from_archive(
+        &scratch.join("data.sah"),
+        &scratch.join("data.saf"),
+    )?;
+    fs.extract(&scratch.join("data"))?;
+
+    let actual = list_files(scratch)?;
+    let expected = collect_dist_files(conn, dist, patch)
+        .await?
+        .into_iter()
+        .map(|f| (f.path.to_lowercase(), f.uncompressed_size as u64))
+        .collect::<BTreeMap<_, _>>();
+
+    let discrepancies = diff_files(&expected, &actual)
+        .into_iter()
+        .filter(|d| match d {
+            Discrepancy::Extra { path } => !GENERATED_FILES.contains(&path.as_str()),
+            Discrepancy::SizeMismatch { path, .. } => !REWRITTEN_FILES.contains(&path.as_str()),
+            Discrepancy::Missing { .. } => true,
+        })
+        .collect();
+
+    Ok(VerifyReport {
+        file_count: actual.len(),
+        discrepancies,
+    })
+}
+
+/// Verifies that a freshly built archive filesystem can be read back, and that it contains exactly
+/// the files (and sizes) of the directory it was built from.
+///
+/// # Arguments
+/// * `header`  - The path to the built `data.sah`.
+/// * `data`    - The built `data.saf`.
+/// * `source`  - The directory the archive was built from.
+/// * `scratch` - A directory to extract the archive into. This is removed once verified.
+pub(crate) fn verify_round_trip(
+    header: &Path,
+    data: &DataFile,
+    source: &Path,
+    scratch: &Path,
+) -> anyhow::Result<()> {
+    fs::create_dir_all(scratch)?;
+    let result = (|| -> anyhow::Result<()> {
+        let data_path = match data {
+            DataFile::Memory(data) => {
+                let data_path = scratch.join("data.saf");
+                fs::write(&data_path, data)?;
+                data_path
+            }
+            DataFile::Disk(path) => path.clone(),
+        };
+
+        let extract_path = scratch.join("data");
+        let fs = libclient::fs::Filesystem::from_archive(header, &data_path)?;
+        fs.extract(&extract_path)?;
+
+        let discrepancies = diff_files(&list_files(source)?, &list_files(&extract_path)?);
+        if !discrepancies.is_empty() {
+            let list = discrepancies
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ");
+            return Err(anyhow!(
+                "built archive doesn't match its source ({} discrepancies): {}",
+                discrepancies.len(),
+                list
+            ));
+        }
+        Ok(())
+    })();
+    fs::remove_dir_all(scratch)?;
+    result
+}
+
+/// Compares an expected set of files and sizes against an actual set.
+///
+/// # Arguments
+/// * `expected`    - The expected files, and their sizes.
+/// * `actual`      - The actual files, and their sizes.
+fn diff_files(
+    expected: &BTreeMap<String, u64>,
+    actual: &BTreeMap<String, u64>,
+) -> Vec<Discrepancy> {
+    let mut discrepancies = expected
+        .iter()
+        .filter_map(|(path, &expected_size)| match actual.get(path) {
+            None => Some(Discrepancy::Missing { path: path.clone() }),
+            Some(&actual_size) if actual_size != expected_size => Some(Discrepancy::SizeMismatch {
+                path: path.clone(),
+                expected: expected_size,
+                actual: actual_size,
+            }),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    discrepancies.extend(
+        actual
+            .keys()
+            .filter(|path| !expected.contains_key(*path))
+            .map(|path| Discrepancy::Extra { path: path.clone() }),
+    );
+    discrepancies
+}
+
+/// Recursively lists the files in a directory, keyed by their lowercase path relative to `root`
+/// (using `/` as the separator, to match the database), along with their sizes.
+///
+/// # Arguments
+/// * `root`    - The directory to list.
+pub(crate) fn list_files(root: &Path) -> anyhow::Result<BTreeMap<String, u64>> {
+    let mut files = BTreeMap::new();
+    let mut pending = vec![root.to_path_buf()];
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)? {
+            let entry = entry?;
+            let metadata = entry.metadata()?;
+            if metadata.is_dir() {
+                pending.push(entry.path());
+                continue;
+            }
+
+            let path = entry.path();
+            let relative = path
+                .strip_prefix(root)?
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_lowercase())
+                .collect::<Vec<_>>()
+                .join("/");
+            files.insert(relative, metadata.len());
+        }
+    }
+    Ok(files)
+}
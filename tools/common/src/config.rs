@@ -0,0 +1,86 @@
+use anyhow::anyhow;
+use ini::Ini;
+use std::path::{Path, PathBuf};
+
+/// The default settings for the tools, loaded from an INI file. Every value is optional, and
+/// anything passed as a command-line flag takes precedence over the value in the file.
+///
+/// ```ini
+/// [paths]
+/// patch_dir   = /mnt/archive/patches
+/// inflate_dir = /mnt/archive/inflated
+/// archive     = /mnt/archive
+/// database    = /mnt/archive/api/archive.sqlite
+///
+/// [tools]
+/// concurrency = 8
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct Config {
+    /// The directory containing the downloaded patch files.
+    pub patch_dir: Option<PathBuf>,
+    /// The directory to inflate patches into.
+    pub inflate_dir: Option<PathBuf>,
+    /// The root of a local copy of the archive.
+    pub archive_path: Option<PathBuf>,
+    /// The path to the archive database.
+    pub database: Option<PathBuf>,
+    /// The number of parallel workers to use.
+    pub concurrency: Option<usize>,
+}
+
+impl Config {
+    /// Loads the config from an INI file.
+    ///
+    /// # Arguments
+    /// * `path`    - The path to the config file.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let ini = Ini::load_from_file(path)
+            .map_err(|e| anyhow!("failed to load config {:?}: {}", path, e))?;
+        let get = |section: &str, key: &str| {
+            ini.section(Some(section))
+                .and_then(|s| s.get(key))
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(str::to_string)
+        };
+
+        let concurrency = get("tools", "concurrency")
+            .map(|v| v.parse::<usize>())
+            .transpose()
+            .map_err(|e| anyhow!("invalid `concurrency` in config {:?}: {}", path, e))?;
+
+        Ok(Self {
+            patch_dir: get("paths", "patch_dir").map(PathBuf::from),
+            inflate_dir: get("paths", "inflate_dir").map(PathBuf::from),
+            archive_path: get("paths", "archive").map(PathBuf::from),
+            database: get("paths", "database").map(PathBuf::from),
+            concurrency,
+        })
+    }
+
+    /// Loads the config from an INI file if a path was provided, or returns an empty config
+    /// otherwise.
+    ///
+    /// # Arguments
+    /// * `path`    - The optional path to the config file.
+    pub fn load_or_default(path: Option<&Path>) -> anyhow::Result<Self> {
+        path.map(Self::load).unwrap_or_else(|| Ok(Self::default()))
+    }
+}
+
+/// Resolves a setting, preferring the value from the command-line over the config file, and
+/// erroring if neither provided it.
+///
+/// # Arguments
+/// * `flag`    - The value passed on the command-line.
+/// * `config`  - The value from the config file.
+/// * `name`    - The name of the setting, for the error message.
+pub fn resolve<T>(flag: Option<T>, config: Option<T>, name: &str) -> anyhow::Result<T> {
+    flag.or(config).ok_or_else(|| {
+        anyhow!(
+            "no value for `{}`; pass it as a flag or set it in the config file",
+            name
+        )
+    })
+}
@@ -0,0 +1,41 @@
+use serde::{Deserialize, Serialize};
+use strum_macros::{Display, EnumIter, EnumString, IntoStaticStr};
+
+/// A distribution of the game, run by a different publisher. The lowercase display form (i.e.
+/// `us`) is what identifies the distribution in the archive database and patch server urls.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Display,
+    EnumIter,
+    EnumString,
+    IntoStaticStr,
+    Deserialize,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "snake_case")]
+pub enum Distribution {
+    Us,
+    De,
+    Es,
+    Pt,
+    Ga,
+}
+
+impl Distribution {
+    /// Gets a distribution from the name of its directory in the archive, which is `shaiya-<name>`.
+    /// This is usually the distribution's identifier, but some are named after their publisher.
+    ///
+    /// # Arguments
+    /// * `name`    - The directory name, without the `shaiya-` prefix (i.e. `gamigo`).
+    pub fn from_directory_name(name: &str) -> Option<Self> {
+        match name {
+            "gamigo" => Some(Self::Ga),
+            name => name.parse().ok(),
+        }
+    }
+}
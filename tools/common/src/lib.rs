@@ -0,0 +1,4 @@
+pub mod config;
+pub mod distribution;
+
+pub use distribution::Distribution;
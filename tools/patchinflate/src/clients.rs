@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The name of the manifest recording which file in the client directory holds each patch's
+/// client.
+const MANIFEST_NAME: &str = "clients.json";
+
+/// A patch's client, as recorded in the manifest.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ClientEntry {
+    /// The SHA-256 of the client.
+    sha256: String,
+    /// The name of the file in the client directory holding the client, which is a client from an
+    /// earlier patch if the client didn't change.
+    file: String,
+}
+
+/// An index of the game clients copied out of patches, keyed by their contents. Most patches
+/// don't change the client, so an identical client is only copied once, and the manifest records
+/// which copy each patch's client maps to.
+pub(crate) struct ClientIndex {
+    dir: PathBuf,
+    manifest: BTreeMap<String, ClientEntry>,
+    files: HashMap<String, String>,
+}
+
+impl ClientIndex {
+    /// Loads the index for a client directory, from its manifest and any clients already in it.
+    ///
+    /// # Arguments
+    /// * `dir` - The client directory.
+    pub(crate) fn load(dir: &Path) -> anyhow::Result<Self> {
+        let manifest_path = dir.join(MANIFEST_NAME);
+        let manifest: BTreeMap<String, ClientEntry> = if manifest_path.is_file() {
+            serde_json::from_slice(&fs::read(&manifest_path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        let mut files = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap().to_string_lossy().to_string();
+            if path.is_file() && name.to_lowercase().ends_with(".exe") {
+                let sha256 = format!("{:x}", Sha256::digest(&fs::read(&path)?));
+                files.entry(sha256).or_insert(name);
+            }
+        }
+
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            manifest,
+            files,
+        })
+    }
+
+    /// Adds a patch's client to the index. The client is only written to the client directory if
+    /// no identical client is already there.
+    ///
+    /// # Arguments
+    /// * `name`    - The name of the patch's client (i.e. `ps0430-12-3-2012-game.exe`).
+    /// * `data`    - The client.
+    pub(crate) fn add(&mut self, name: &str, data: &[u8]) -> anyhow::Result<()> {
+        let sha256 = format!("{:x}", Sha256::digest(data));
+        let file = match self.files.get(&sha256) {
+            Some(existing) => {
+                tracing::info!(client = name, %existing, "client unchanged; skipping copy");
+                existing.clone()
+            }
+            None => {
+                fs::write(self.dir.join(name), data)?;
+                self.files.insert(sha256.clone(), name.to_string());
+                name.to_string()
+            }
+        };
+        self.manifest
+            .insert(name.to_string(), ClientEntry { sha256, file });
+        Ok(())
+    }
+
+    /// Writes the manifest to the client directory.
+    pub(crate) fn save(&self) -> anyhow::Result<()> {
+        let manifest = serde_json::to_vec_pretty(&self.manifest)?;
+        fs::write(self.dir.join(MANIFEST_NAME), manifest)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty client directory for a test to write to.
+    ///
+    /// # Arguments
+    /// * `name`    - The name of the test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("clients-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Lists the files in a client directory, sorted by name.
+    ///
+    /// # Arguments
+    /// * `dir` - The client directory.
+    fn list_files(dir: &Path) -> Vec<String> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
+    #[test]
+    fn copies_only_distinct_clients() {
+        let dir = test_dir("distinct");
+        let mut index = ClientIndex::load(&dir).unwrap();
+        index.add("ps0001-1-1-2008-game.exe", b"client").unwrap();
+        index.add("ps0002-2-1-2008-game.exe", b"client").unwrap();
+        index.add("ps0003-3-1-2008-game.exe", b"patched").unwrap();
+
+        assert_eq!(
+            list_files(&dir),
+            ["ps0001-1-1-2008-game.exe", "ps0003-3-1-2008-game.exe"]
+        );
+        assert_eq!(
+            index.manifest["ps0002-2-1-2008-game.exe"].file,
+            "ps0001-1-1-2008-game.exe"
+        );
+        assert_eq!(
+            index.manifest["ps0003-3-1-2008-game.exe"].file,
+            "ps0003-3-1-2008-game.exe"
+        );
+        assert_eq!(
+            index.manifest["ps0001-1-1-2008-game.exe"].sha256,
+            format!("{:x}", Sha256::digest(b"client"))
+        );
+    }
+
+    #[test]
+    fn indexes_clients_from_an_earlier_run() {
+        let dir = test_dir("reload");
+        let mut index = ClientIndex::load(&dir).unwrap();
+        index.add("ps0001-1-1-2008-game.exe", b"client").unwrap();
+        index.save().unwrap();
+
+        // Clients copied by hand are indexed too, whatever the case of their extension, but
+        // files which aren't clients are not.
+        fs::write(dir.join("ps0002-2-1-2008-Game.EXE"), b"patched").unwrap();
+        fs::write(dir.join("notes.txt"), b"notes").unwrap();
+
+        let mut index = ClientIndex::load(&dir).unwrap();
+        assert!(index.manifest.contains_key("ps0001-1-1-2008-game.exe"));
+        index.add("ps0003-3-1-2008-game.exe", b"client").unwrap();
+        index.add("ps0004-4-1-2008-game.exe", b"patched").unwrap();
+        index.add("ps0005-5-1-2008-game.exe", b"notes").unwrap();
+        index.save().unwrap();
+
+        let manifest: BTreeMap<String, ClientEntry> =
+            serde_json::from_slice(&fs::read(dir.join(MANIFEST_NAME)).unwrap()).unwrap();
+        let files = manifest
+            .iter()
+            .map(|(name, entry)| (name.as_str(), entry.file.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            files,
+            [
+                ("ps0001-1-1-2008-game.exe", "ps0001-1-1-2008-game.exe"),
+                ("ps0003-3-1-2008-game.exe", "ps0001-1-1-2008-game.exe"),
+                ("ps0004-4-1-2008-game.exe", "ps0002-2-1-2008-Game.EXE"),
+                ("ps0005-5-1-2008-game.exe", "ps0005-5-1-2008-game.exe"),
+            ]
+        );
+    }
+}
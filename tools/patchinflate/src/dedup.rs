@@ -0,0 +1,266 @@
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The name of the record of merged patch directories, written alongside the inflated patches. It
+/// maps the name of each removed directory to the one it was merged into, so that a later run
+/// doesn't inflate the removed directory again.
+const MERGED_NAME: &str = "merged.json";
+
+/// The outcome of reconciling the directories inflated for a single patch number.
+#[derive(Debug)]
+pub enum Reconciliation {
+    /// The directories had identical contents, so only `kept` was retained.
+    Merged {
+        kept: PathBuf,
+        removed: Vec<PathBuf>,
+    },
+    /// The directories had different contents, and need to be reviewed by hand.
+    Conflicting { dirs: Vec<PathBuf> },
+}
+
+impl fmt::Display for Reconciliation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Reconciliation::Merged { kept, removed } => {
+                write!(f, "merged {:?} into {:?}", removed, kept)
+            }
+            Reconciliation::Conflicting { dirs } => {
+                write!(f, "needs review, contents differ: {:?}", dirs)
+            }
+        }
+    }
+}
+
+/// Finds patch directories which share a patch number (i.e. `ps0430-12-3-2012` and
+/// `ps0430-14-3-2012`, from a patch that was repacked upstream), and reconciles them. Directories
+/// with identical contents are merged by keeping the earliest-dated one, and any with differing
+/// contents are left in place and reported. Each merge is recorded in `merged.json`, which the
+/// inflater checks before inflating a patch.
+///
+/// # Arguments
+/// * `patch_dir`   - The directory containing the inflated patches.
+pub fn dedup_patches(patch_dir: &Path) -> anyhow::Result<Vec<Reconciliation>> {
+    let mut merged = load_merged(patch_dir)?;
+    let mut groups: BTreeMap<String, Vec<(PathBuf, (u32, u32, u32))>> = BTreeMap::new();
+    for entry in fs::read_dir(patch_dir)? {
+        let path = entry?.path();
+        if !path.is_dir() {
+            continue;
+        }
+
+        if let Some((patch, date)) = parse_patch_name(&dir_name(&path)) {
+            groups.entry(patch).or_default().push((path, date));
+        }
+    }
+
+    let mut results = Vec::new();
+    for (patch, mut dirs) in groups.into_iter().filter(|(_, dirs)| dirs.len() > 1) {
+        dirs.sort_by_key(|(_, date)| *date);
+        let hashes = dirs
+            .iter()
+            .map(|(dir, _)| hash_dir(dir))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        let dirs = dirs.into_iter().map(|(dir, _)| dir).collect::<Vec<_>>();
+        if hashes.iter().all(|hash| *hash == hashes[0]) {
+            let mut dirs = dirs.into_iter();
+            let kept = dirs.next().unwrap();
+            let removed = dirs.collect::<Vec<_>>();
+            for dir in &removed {
+                fs::remove_dir_all(dir)?;
+                merged.insert(dir_name(dir), dir_name(&kept));
+            }
+            tracing::info!(%patch, ?kept, ?removed, "merged identical patch directories");
+            results.push(Reconciliation::Merged { kept, removed });
+        } else {
+            tracing::warn!(%patch, ?dirs, "patch directories differ; review required");
+            results.push(Reconciliation::Conflicting { dirs });
+        }
+    }
+
+    fs::write(
+        patch_dir.join(MERGED_NAME),
+        serde_json::to_vec_pretty(&merged)?,
+    )?;
+    Ok(results)
+}
+
+/// Loads the record of merged patch directories, keyed by the name of each removed directory,
+/// with the name of the directory it was merged into.
+///
+/// # Arguments
+/// * `patch_dir`   - The directory containing the inflated patches.
+pub(crate) fn load_merged(patch_dir: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+    let path = patch_dir.join(MERGED_NAME);
+    if path.is_file() {
+        Ok(serde_json::from_slice(&fs::read(&path)?)?)
+    } else {
+        Ok(BTreeMap::new())
+    }
+}
+
+/// Gets the name of a patch directory.
+///
+/// # Arguments
+/// * `dir` - The directory.
+fn dir_name(dir: &Path) -> String {
+    dir.file_name().unwrap().to_string_lossy().to_string()
+}
+
+/// Parses an inflated patch directory name (`psNNNN-day-month-year`), into the patch prefix and
+/// a sortable `(year, month, day)` date.
+///
+/// # Arguments
+/// * `name`    - The directory name.
+fn parse_patch_name(name: &str) -> Option<(String, (u32, u32, u32))> {
+    let mut parts = name.splitn(4, '-');
+    let patch = parts.next()?;
+    let day = parts.next()?.parse().ok()?;
+    let month = parts.next()?.parse().ok()?;
+    let year = parts.next()?.parse().ok()?;
+    Some((patch.to_string(), (year, month, day)))
+}
+
+/// Hashes the contents of an inflated patch directory. The copy of the original `.patch` file is
+/// excluded, as a repacked patch differs in its zip metadata even when the contents are the same.
+///
+/// # Arguments
+/// * `dir` - The directory to hash.
+fn hash_dir(dir: &Path) -> io::Result<Vec<u8>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else if path.extension().and_then(|e| e.to_str()) != Some("patch") {
+                files.push(path);
+            }
+        }
+    }
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for path in files {
+        let relative = path.strip_prefix(dir).unwrap();
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(&path)?);
+    }
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Creates an empty directory of inflated patches for a test to write to.
+    ///
+    /// # Arguments
+    /// * `name`    - The name of the test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("dedup-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates an inflated patch directory, from files of their relative path and contents.
+    ///
+    /// # Arguments
+    /// * `dir`     - The directory.
+    /// * `files`   - The files.
+    fn patch_fixture(dir: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            let path = dir.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn merges_identical_directories_keeping_the_earliest() {
+        let dir = test_dir("identical");
+        let files = [("game.exe", "client"), ("data/item/item.sdata", "items")];
+        patch_fixture(&dir.join("ps0430-14-3-2012"), &files);
+        patch_fixture(&dir.join("ps0430-12-3-2012"), &files);
+        patch_fixture(&dir.join("ps0430-2-1-2013"), &files);
+        fs::write(dir.join("ps0430-12-3-2012/ps0430.patch"), "original").unwrap();
+        fs::write(dir.join("ps0430-14-3-2012/ps0430.patch"), "repacked").unwrap();
+
+        let results = dedup_patches(&dir).unwrap();
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            Reconciliation::Merged { kept, removed } => {
+                assert_eq!(kept, &dir.join("ps0430-12-3-2012"));
+                assert_eq!(
+                    removed,
+                    &vec![dir.join("ps0430-14-3-2012"), dir.join("ps0430-2-1-2013")]
+                );
+            }
+            other => panic!("expected a merge, got {}", other),
+        }
+        assert!(dir.join("ps0430-12-3-2012/game.exe").is_file());
+        assert!(!dir.join("ps0430-14-3-2012").exists());
+        assert!(!dir.join("ps0430-2-1-2013").exists());
+
+        let merged = load_merged(&dir).unwrap();
+        assert_eq!(merged["ps0430-14-3-2012"], "ps0430-12-3-2012");
+        assert_eq!(merged["ps0430-2-1-2013"], "ps0430-12-3-2012");
+    }
+
+    #[test]
+    fn keeps_earlier_merges_when_run_again() {
+        let dir = test_dir("rerun");
+        patch_fixture(&dir.join("ps0430-12-3-2012"), &[("game.exe", "client")]);
+        patch_fixture(&dir.join("ps0430-14-3-2012"), &[("game.exe", "client")]);
+        dedup_patches(&dir).unwrap();
+
+        patch_fixture(&dir.join("ps0431-1-4-2012"), &[("game.exe", "client")]);
+        patch_fixture(&dir.join("ps0431-3-4-2012"), &[("game.exe", "client")]);
+        dedup_patches(&dir).unwrap();
+
+        let merged = load_merged(&dir).unwrap();
+        assert_eq!(merged["ps0430-14-3-2012"], "ps0430-12-3-2012");
+        assert_eq!(merged["ps0431-3-4-2012"], "ps0431-1-4-2012");
+    }
+
+    #[test]
+    fn leaves_differing_directories_in_place() {
+        let dir = test_dir("differing");
+        patch_fixture(&dir.join("ps0430-12-3-2012"), &[("game.exe", "client")]);
+        patch_fixture(
+            &dir.join("ps0430-14-3-2012"),
+            &[("game.exe", "fixed client")],
+        );
+        patch_fixture(&dir.join("ps0431-1-4-2012"), &[("game.exe", "client")]);
+
+        let results = dedup_patches(&dir).unwrap();
+        assert_eq!(results.len(), 1);
+        match &results[0] {
+            Reconciliation::Conflicting { dirs } => assert_eq!(
+                dirs,
+                &vec![dir.join("ps0430-12-3-2012"), dir.join("ps0430-14-3-2012")]
+            ),
+            other => panic!("expected a conflict, got {}", other),
+        }
+        assert!(dir.join("ps0430-12-3-2012").is_dir());
+        assert!(dir.join("ps0430-14-3-2012").is_dir());
+        assert!(load_merged(&dir).unwrap().is_empty());
+    }
+
+    #[test]
+    fn parses_patch_directory_names() {
+        assert_eq!(
+            parse_patch_name("ps0430-12-3-2012"),
+            Some(("ps0430".to_string(), (2012, 3, 12)))
+        );
+        assert_eq!(parse_patch_name("summary"), None);
+        assert_eq!(parse_patch_name("ps0430-x-3-2012"), None);
+    }
+}
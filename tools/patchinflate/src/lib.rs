@@ -0,0 +1,773 @@
+mod clients;
+mod dedup;
+
+pub use dedup::{dedup_patches, Reconciliation};
+
+use crate::clients::ClientIndex;
+use anyhow::anyhow;
+use common::Distribution;
+use indicatif::{ProgressBar, ProgressStyle};
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use regex::Regex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::OsStr;
+use std::fs;
+use std::io;
+use std::io::{BufReader, Read, Seek};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use zip::{DateTime, ZipArchive};
+
+/// The file names of the game client, as shipped by patches, if none are configured. These are
+/// matched case-insensitively.
+pub const DEFAULT_CLIENT_NAMES: &[&str] = &["game.exe", "shaiya.exe"];
+
+/// The name of the report written alongside the inflated patches (i.e. to `patches/<dist>/`),
+/// summarising each inflated patch.
+pub const SUMMARY_NAME: &str = "summary.json";
+
+/// The most a single patch can be extracted to, if not configured (8 GiB).
+pub const DEFAULT_MAX_TOTAL_SIZE: u64 = 8 * 1024 * 1024 * 1024;
+
+/// The most a single entry in a patch can be extracted to, if not configured (4 GiB).
+pub const DEFAULT_MAX_ENTRY_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// The limits on how much a patch can be extracted to, which stop a corrupt or malicious patch
+/// from filling the disk.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    /// The most the entries of a patch can be extracted to, in total, in bytes.
+    pub max_total_size: u64,
+    /// The most a single entry of a patch can be extracted to, in bytes.
+    pub max_entry_size: u64,
+}
+
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        Self {
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_entry_size: DEFAULT_MAX_ENTRY_SIZE,
+        }
+    }
+}
+
+/// The options for inflating a directory of patches.
+#[derive(Debug, Clone)]
+pub struct InflateOptions {
+    /// The directory containing the patch files.
+    pub patch_dir: PathBuf,
+    /// The directory to extract the patch files to.
+    pub inflate_dir: PathBuf,
+    /// The distribution the patches belong to. If not provided, this is detected from a
+    /// `shaiya-<dist>` component of the patch directory.
+    pub dist: Option<Distribution>,
+    /// The number of threads to inflate patches with. `0` uses one thread per core.
+    pub threads: usize,
+    /// The file names of the game client, matched case-insensitively. If empty,
+    /// `DEFAULT_CLIENT_NAMES` is used.
+    pub client_names: Vec<String>,
+    /// Whether to re-inflate patches whose output directory already exists. Otherwise, they're
+    /// skipped.
+    pub force: bool,
+    /// The limits on how much each patch can be extracted to.
+    pub limits: ExtractLimits,
+}
+
+/// The directories that patches were inflated into.
+#[derive(Debug, Clone)]
+pub struct InflateOutput {
+    /// The directory containing the inflated patches.
+    pub patch_dir: PathBuf,
+    /// The directory containing the copied game clients.
+    pub client_dir: PathBuf,
+    /// A summary of each inflated patch, ordered by patch name.
+    pub summaries: Vec<PatchSummary>,
+}
+
+/// A summary of what was found when inflating a single patch.
+#[derive(Debug, Clone, Serialize)]
+pub struct PatchSummary {
+    /// The file name of the patch (i.e. `ps0430.patch`).
+    pub patch: String,
+    /// The name of the directory the patch was inflated into (i.e. `ps0430-12-3-2012`), or the
+    /// file stem of the patch if it couldn't be read.
+    pub patch_name: String,
+    /// The number of files extracted from each archive filesystem in the patch, keyed by the
+    /// directory it was extracted to.
+    pub archives: BTreeMap<String, usize>,
+    /// The names of the game clients the patch shipped.
+    pub clients: Vec<String>,
+    /// Whether the patch was skipped, as it had already been inflated.
+    pub skipped: bool,
+    /// Why the patch failed to inflate, if it did. A failed patch's output directory is removed.
+    pub error: Option<String>,
+}
+
+/// Inflates every patch in a directory, organising them into directories named after the patch
+/// number and date.
+///
+/// # Arguments
+/// * `options` - The inflate options.
+pub fn inflate(options: &InflateOptions) -> anyhow::Result<InflateOutput> {
+    // If the `patch_dir` is not a valid directory, we should return early.
+    if let Ok(metadata) = fs::metadata(&options.patch_dir) {
+        if !metadata.is_dir() {
+            return Err(anyhow!(
+                "patch directory {:?} is not a directory",
+                options.patch_dir
+            ));
+        }
+    }
+
+    // Create the output directories, nested by distribution when we know it.
+    let mut patch_dir = options.inflate_dir.join("patches");
+    let mut client_dir = options.inflate_dir.join("clients");
+    let detected = fs::canonicalize(&options.patch_dir)
+        .ok()
+        .and_then(|path| detect_distribution(&path));
+    match options.dist.or(detected) {
+        Some(dist) => {
+            tracing::info!(%dist, "inflating patches for distribution");
+            patch_dir = patch_dir.join(dist.to_string());
+            client_dir = client_dir.join(dist.to_string());
+        }
+        None => tracing::warn!(
+            patch_dir = ?options.patch_dir,
+            "couldn't determine the distribution; falling back to a flat output layout"
+        ),
+    }
+    fs::create_dir_all(&patch_dir)?;
+    fs::create_dir_all(&client_dir)?;
+
+    // Collect all of the patch files in the input directory. Files without a recognisable patch
+    // number are skipped, as there'd be nothing to name their output directory after.
+    let patches = fs::read_dir(&options.patch_dir)?
+        .filter_map(Result::ok)
+        .filter(|d| d.metadata().is_ok_and(|m| m.is_file()))
+        .map(|d| d.path())
+        .filter(|path| has_extension(path, "patch") || has_extension(path, "zip"))
+        .filter_map(|path| match parse_patch_number(&path) {
+            Some(number) => Some((path, number)),
+            None => {
+                tracing::warn!(
+                    ?path,
+                    "couldn't find a patch number in the file name; skipping"
+                );
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Track the number of inflated patches, and the one most recently started.
+    let progress = ProgressBar::new(patches.len() as u64);
+    progress.set_style(
+        ProgressStyle::with_template(
+            "[{elapsed_precise}] {bar:40.cyan/blue} {pos}/{len} patches (eta {eta}) {msg}",
+        )?
+        .progress_chars("##-"),
+    );
+
+    let client_names = if options.client_names.is_empty() {
+        DEFAULT_CLIENT_NAMES
+            .iter()
+            .map(|name| name.to_string())
+            .collect()
+    } else {
+        options.client_names.clone()
+    };
+    let client_names = client_names
+        .iter()
+        .map(|name| name.to_lowercase())
+        .collect::<Vec<_>>();
+
+    // Index the clients already copied out, so that unchanged clients aren't copied again.
+    let clients = Mutex::new(ClientIndex::load(&client_dir)?);
+
+    // Patches whose directories were merged into another by `dedup_patches` aren't inflated again.
+    let merged = dedup::load_merged(&patch_dir)?;
+
+    // Iterate over each patch and inflate it.
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(options.threads)
+        .build()?;
+    let mut summaries = pool.install(|| {
+        patches
+            .par_iter()
+            .map(|(path, number)| {
+                if let Some(name) = path.file_name() {
+                    progress.set_message(name.to_string_lossy().to_string());
+                }
+                // A patch that fails to inflate is recorded, rather than stopping the whole batch.
+                let summary = inflate_patch(
+                    path,
+                    *number,
+                    &patch_dir,
+                    &client_names,
+                    &clients,
+                    &merged,
+                    options,
+                )
+                .unwrap_or_else(|e| {
+                    let error = format!("{:#}", e);
+                    tracing::error!(?path, %error, "failed to inflate patch");
+                    PatchSummary {
+                        patch: path.file_name().unwrap().to_string_lossy().to_string(),
+                        patch_name: path.file_stem().unwrap().to_string_lossy().to_string(),
+                        archives: BTreeMap::new(),
+                        clients: Vec::new(),
+                        skipped: false,
+                        error: Some(error),
+                    }
+                });
+                progress.inc(1);
+                summary
+            })
+            .collect::<Vec<_>>()
+    });
+    progress.finish_with_message("done");
+    clients.into_inner().unwrap().save()?;
+
+    // Write a report of what each patch contained, so that large runs can be audited. It's kept
+    // with the distribution's patches, so that runs for other distributions don't replace it.
+    let summary_path = patch_dir.join(SUMMARY_NAME);
+    let failed = summaries.iter().filter(|s| s.error.is_some()).count();
+    if failed > 0 {
+        tracing::warn!(
+            failed,
+            "some patches failed to inflate; see {:?}",
+            summary_path
+        );
+    }
+
+    summaries.sort_by(|a, b| a.patch_name.cmp(&b.patch_name));
+    fs::write(&summary_path, serde_json::to_vec_pretty(&summaries)?)?;
+    Ok(InflateOutput {
+        patch_dir,
+        client_dir,
+        summaries,
+    })
+}
+
+/// Detects the distribution of a patch directory, from the nearest `shaiya-<dist>` path component
+/// (the layout produced when mirroring patches) that names a known distribution.
+///
+/// # Arguments
+/// * `path`    - The patch directory.
+fn detect_distribution(path: &Path) -> Option<Distribution> {
+    let re = Regex::new(r"^shaiya-([a-z]+)$").unwrap();
+    path.components().rev().find_map(|c| {
+        let name = c.as_os_str().to_str()?.to_lowercase();
+        Distribution::from_directory_name(&re.captures(&name)?[1])
+    })
+}
+
+/// Parses the patch number from the file name of a patch, as named by the patch server (i.e.
+/// `ps0430.patch`) or by community archives (i.e. `Patch_1234.zip`, or `ps12345.patch`).
+///
+/// # Arguments
+/// * `path`    - The path to the patch file.
+fn parse_patch_number(path: &Path) -> Option<u32> {
+    let re = Regex::new(r"(?i)(?:^|[^a-z])(?:ps|patch)[_\- ]?(\d{4,})").unwrap();
+    let stem = path.file_stem()?.to_str()?;
+    re.captures(stem)?[1].parse().ok()
+}
+
+fn inflate_patch(
+    path: &Path,
+    number: u32,
+    patch_dir: &Path,
+    client_names: &[String],
+    clients: &Mutex<ClientIndex>,
+    merged: &BTreeMap<String, String>,
+    options: &InflateOptions,
+) -> anyhow::Result<PatchSummary> {
+    let patch = format!("ps{:04}", number);
+    let file = fs::File::open(path)?;
+
+    // Parse the patch file as a zip archive.
+    let reader = BufReader::new(&file);
+    let mut zip = ZipArchive::new(reader)?;
+
+    // Find the most recent date within the archive. Entries with an invalid date are ignored.
+    let mut date = DateTime::default();
+    (0..zip.len()).for_each(|idx| {
+        if let Ok(file) = zip.by_index(idx) {
+            if let (Ok(mtime), Ok(latest)) = (file.last_modified().to_time(), date.to_time()) {
+                if mtime > latest {
+                    date = file.last_modified();
+                }
+            }
+        }
+    });
+
+    // Include the most recent date in the patch name.
+    let patch_name = format!("{}-{}-{}-{}", patch, date.day(), date.month(), date.year());
+
+    let mut summary = PatchSummary {
+        patch: path.file_name().unwrap().to_string_lossy().to_string(),
+        patch_name,
+        archives: BTreeMap::new(),
+        clients: Vec::new(),
+        skipped: false,
+        error: None,
+    };
+
+    // If the patch has already been inflated, there's nothing to do. The name includes the
+    // patch's date, so a repacked patch is still inflated.
+    let patch_out_dir = patch_dir.join(&summary.patch_name);
+    if !options.force
+        && fs::read_dir(&patch_out_dir).is_ok_and(|mut entries| entries.next().is_some())
+    {
+        tracing::info!(patch = %summary.patch_name, "patch already inflated; skipping");
+        summary.skipped = true;
+        return Ok(summary);
+    }
+
+    // A patch whose directory was merged into an identical one is skipped as long as that
+    // directory is still there.
+    if let Some(kept) = merged.get(&summary.patch_name) {
+        if !options.force && patch_dir.join(kept).is_dir() {
+            tracing::info!(patch = %summary.patch_name, %kept, "patch merged by dedup; skipping");
+            summary.skipped = true;
+            return Ok(summary);
+        }
+    }
+
+    // Create the output directory.
+    fs::create_dir_all(&patch_out_dir)?;
+
+    // A partially inflated patch would be skipped by later runs, so the output directory is
+    // removed if inflating fails.
+    if let Err(e) = populate_patch_dir(
+        path,
+        &mut zip,
+        &patch_out_dir,
+        client_names,
+        clients,
+        options,
+        &mut summary,
+    ) {
+        fs::remove_dir_all(&patch_out_dir)?;
+        return Err(e);
+    }
+    Ok(summary)
+}
+
+/// Populates the output directory of a patch, with the patch file, its extracted contents, and
+/// any archive filesystems within it. Any game clients are added to the client index.
+///
+/// # Arguments
+/// * `path`            - The path to the patch file.
+/// * `zip`             - The patch archive.
+/// * `patch_out_dir`   - The output directory of the patch.
+/// * `client_names`    - The lowercase file names of the game client.
+/// * `clients`         - The index of copied game clients.
+/// * `options`         - The inflate options.
+/// * `summary`         - The summary of the patch, which is filled in.
+fn populate_patch_dir<R: Read + Seek>(
+    path: &Path,
+    zip: &mut ZipArchive<R>,
+    patch_out_dir: &Path,
+    client_names: &[String],
+    clients: &Mutex<ClientIndex>,
+    options: &InflateOptions,
+    summary: &mut PatchSummary,
+) -> anyhow::Result<()> {
+    // Copy the patch file, to the patch directory.
+    fs::copy(
+        path,
+        patch_out_dir.join(path.file_name().unwrap().to_str().unwrap()),
+    )?;
+
+    // Extract the contents of the patch, to the destination
+    extract_zip(zip, patch_out_dir, options.limits)?;
+
+    // If the patch contains any archive filesystems, we'll extract them.
+    summary.archives = extract_archives(patch_out_dir)?;
+
+    // If the patch contains a game client, we'll create a copy in the `client_dir`, unless an
+    // identical client is already there.
+    for entry in fs::read_dir(patch_out_dir)? {
+        let client_file = entry?.path();
+        let name = match client_file.file_name().and_then(|name| name.to_str()) {
+            Some(name) => name.to_lowercase(),
+            None => continue,
+        };
+        if client_file.is_file() && client_names.contains(&name) {
+            let client_buf = fs::read(&client_file)?;
+            clients
+                .lock()
+                .unwrap()
+                .add(&format!("{}-{}", summary.patch_name, name), &client_buf)?;
+            summary.clients.push(name);
+        }
+    }
+    summary.clients.sort();
+    Ok(())
+}
+
+/// Extracts every archive filesystem (a `.sah` header, and its `.saf` data file) in an inflated
+/// patch directory, deleting the archive files afterwards. The usual `update` archive is extracted
+/// to `data`, and any others to a directory named after the archive. Headers without a data file
+/// are logged and left in place. Returns the number of files extracted from each archive, keyed by
+/// the directory it was extracted to.
+///
+/// # Arguments
+/// * `patch_out_dir`   - The inflated patch directory.
+fn extract_archives(patch_out_dir: &Path) -> anyhow::Result<BTreeMap<String, usize>> {
+    let mut headers = fs::read_dir(patch_out_dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && has_extension(path, "sah"))
+        .collect::<Vec<_>>();
+    headers.sort();
+
+    let mut extracted = BTreeMap::new();
+    for header_file in headers {
+        let data_file = match find_data_file(&header_file) {
+            Some(data_file) => data_file,
+            None => {
+                tracing::warn!(?header_file, "archive header has no data file; skipping");
+                continue;
+            }
+        };
+
+        let stem = header_file
+            .file_stem()
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let out_name = if stem.eq_ignore_ascii_case("update") {
+            "data".to_string()
+        } else {
+            stem
+        };
+        let out_dir = patch_out_dir.join(&out_name);
+
+        let fs = libclient::fs::Filesystem::from_archive(&header_file, &data_file)?;
+        fs.extract(&out_dir)?;
+        extracted.insert(out_name, count_files(&out_dir)?);
+
+        // Delete the archive files.
+        fs::remove_file(&header_file)?;
+        fs::remove_file(&data_file)?;
+    }
+    Ok(extracted)
+}
+
+/// Counts the files in a directory tree.
+///
+/// # Arguments
+/// * `dir` - The directory.
+fn count_files(dir: &Path) -> io::Result<usize> {
+    let mut count = 0;
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                pending.push(path);
+            } else {
+                count += 1;
+            }
+        }
+    }
+    Ok(count)
+}
+
+/// Finds the `.saf` data file alongside an archive header, matching the extension
+/// case-insensitively.
+///
+/// # Arguments
+/// * `header_file` - The path to the `.sah` header.
+fn find_data_file(header_file: &Path) -> Option<PathBuf> {
+    let stem = header_file.file_stem()?;
+    fs::read_dir(header_file.parent()?)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .find(|path| path.is_file() && path.file_stem() == Some(stem) && has_extension(path, "saf"))
+}
+
+/// Checks if a path has an extension, ignoring case.
+///
+/// # Arguments
+/// * `path`        - The path.
+/// * `extension`   - The extension, without a leading `.`.
+fn has_extension(path: &Path, extension: &str) -> bool {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .is_some_and(|e| e.eq_ignore_ascii_case(extension))
+}
+
+/// Extracts the contents of a patch archive to a directory.
+///
+/// Directory entries are created as directories, and files keep their modification time from the
+/// archive. Some old patches contain more than one entry for the same path; these are applied in
+/// archive order, so the last entry wins (as it would have when the patcher applied it), and a
+/// warning is logged. If every entry sits under a single top-level directory, that directory is
+/// stripped. Entries with paths that would escape the destination are skipped.
+///
+/// Extraction is aborted if an entry, or the patch as a whole, exceeds the size limits. This is
+/// checked as the data is decompressed, as the sizes declared in the archive can't be trusted.
+///
+/// # Arguments
+/// * `zip`     - The patch archive.
+/// * `dest`    - The directory to extract to.
+/// * `limits`  - The limits on how much the patch can be extracted to.
+fn extract_zip<R: Read + Seek>(
+    zip: &mut ZipArchive<R>,
+    dest: &Path,
+    limits: ExtractLimits,
+) -> anyhow::Result<()> {
+    let mut entries = Vec::with_capacity(zip.len());
+    for idx in 0..zip.len() {
+        let file = zip.by_index(idx)?;
+        match file.enclosed_name() {
+            Some(name) => entries.push((idx, name.to_path_buf(), file.is_dir())),
+            None => tracing::warn!(name = file.name(), "skipping entry with an unsafe path"),
+        }
+    }
+
+    let toplevel = common_toplevel(&entries);
+    let mut written = HashSet::new();
+    let mut total_size = 0u64;
+    for (idx, name, is_dir) in &entries {
+        let relative = match &toplevel {
+            Some(toplevel) => name.strip_prefix(toplevel)?,
+            None => name.as_path(),
+        };
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+
+        let out = dest.join(relative);
+        if *is_dir {
+            fs::create_dir_all(&out)?;
+            continue;
+        }
+
+        if !written.insert(relative.to_path_buf()) {
+            tracing::warn!(path = ?relative, "duplicate entry in patch; using the later entry");
+        }
+        if let Some(parent) = out.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = zip.by_index(*idx)?;
+        let remaining = limits.max_total_size.saturating_sub(total_size);
+        let limit = limits.max_entry_size.min(remaining);
+        if file.size() > limit {
+            return Err(size_limit_error(relative, file.size(), limits, remaining));
+        }
+
+        // Read one byte past the limit, so that an entry which lies about its size is caught.
+        let mut dst = fs::File::create(&out)?;
+        let size = io::copy(&mut (&mut file).take(limit.saturating_add(1)), &mut dst)?;
+        if size > limit {
+            return Err(size_limit_error(relative, size, limits, remaining));
+        }
+        total_size += size;
+        if let Ok(mtime) = file.last_modified().to_time() {
+            dst.set_modified(SystemTime::from(mtime))?;
+        }
+    }
+    Ok(())
+}
+
+/// Creates the error for an entry which exceeds the extraction size limits.
+///
+/// # Arguments
+/// * `path`        - The path of the entry.
+/// * `size`        - The size of the entry, or as much of it as was read.
+/// * `limits`      - The extraction size limits.
+/// * `remaining`   - The space left in the total size limit, before the entry.
+fn size_limit_error(
+    path: &Path,
+    size: u64,
+    limits: ExtractLimits,
+    remaining: u64,
+) -> anyhow::Error {
+    if size > limits.max_entry_size {
+        anyhow!(
+            "entry {:?} exceeds the maximum entry size of {} bytes",
+            path,
+            limits.max_entry_size
+        )
+    } else {
+        anyhow!(
+            "entry {:?} ({} bytes) exceeds the maximum total size of {} bytes, with {} bytes remaining",
+            path,
+            size,
+            limits.max_total_size,
+            remaining
+        )
+    }
+}
+
+/// Gets the top-level directory that every entry of an archive sits under, if there is one.
+///
+/// # Arguments
+/// * `entries` - The index, path, and whether each entry is a directory.
+fn common_toplevel(entries: &[(usize, PathBuf, bool)]) -> Option<PathBuf> {
+    let mut toplevel: Option<&OsStr> = None;
+    for (_, name, is_dir) in entries {
+        let mut components = name.components();
+        let first = components.next()?.as_os_str();
+        if *toplevel.get_or_insert(first) != first {
+            return None;
+        }
+
+        // A file directly in the root means there is no top-level directory to strip.
+        if components.next().is_none() && !is_dir {
+            return None;
+        }
+    }
+    toplevel.map(PathBuf::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Write};
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    /// Creates an empty directory for a test to write to.
+    ///
+    /// # Arguments
+    /// * `name`    - The name of the test.
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("patchinflate-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// Creates a zip archive, from entries of their name and contents. Directories have no
+    /// contents.
+    ///
+    /// # Arguments
+    /// * `entries` - The entries.
+    fn zip_fixture(entries: &[(&str, Option<&str>)]) -> Vec<u8> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, contents) in entries {
+            match contents {
+                Some(contents) => {
+                    writer.start_file(*name, FileOptions::default()).unwrap();
+                    writer.write_all(contents.as_bytes()).unwrap();
+                }
+                None => writer.add_directory(*name, FileOptions::default()).unwrap(),
+            }
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Renames every entry with a name to another of the same length, by rewriting the archive.
+    /// This produces duplicate entries, which the zip writer won't.
+    ///
+    /// # Arguments
+    /// * `zip`     - The archive.
+    /// * `from`    - The name to replace.
+    /// * `to`      - The name to replace it with.
+    fn rename_entries(zip: &mut [u8], from: &str, to: &str) {
+        assert_eq!(from.len(), to.len());
+        let mut idx = 0;
+        while idx + from.len() <= zip.len() {
+            if &zip[idx..idx + from.len()] == from.as_bytes() {
+                zip[idx..idx + from.len()].copy_from_slice(to.as_bytes());
+            }
+            idx += 1;
+        }
+    }
+
+    #[test]
+    fn extracts_zip_with_duplicate_entries() {
+        let mut zip = zip_fixture(&[
+            ("ps0430/", None),
+            ("ps0430/data/", None),
+            ("ps0430/data/empty/", None),
+            ("ps0430/data/a.txt", Some("first")),
+            ("ps0430/data/b.txt", Some("second")),
+            ("ps0430/game.exe", Some("client")),
+        ]);
+        rename_entries(&mut zip, "ps0430/data/b.txt", "ps0430/data/a.txt");
+
+        let dest = test_dir("duplicate-entries");
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        assert_eq!(archive.len(), 6);
+        extract_zip(&mut archive, &dest, ExtractLimits::default()).unwrap();
+
+        // The top-level directory is stripped, and the later of the duplicate entries wins.
+        assert!(!dest.join("ps0430").exists());
+        assert_eq!(
+            fs::read_to_string(dest.join("data/a.txt")).unwrap(),
+            "second"
+        );
+        assert!(!dest.join("data/b.txt").exists());
+        assert!(dest.join("data/empty").is_dir());
+        assert_eq!(fs::read_to_string(dest.join("game.exe")).unwrap(), "client");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn keeps_zip_root_without_a_single_toplevel_directory() {
+        let zip = zip_fixture(&[("game.exe", Some("client")), ("data/a.txt", Some("a"))]);
+
+        let dest = test_dir("no-toplevel");
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        extract_zip(&mut archive, &dest, ExtractLimits::default()).unwrap();
+
+        assert_eq!(fs::read_to_string(dest.join("game.exe")).unwrap(), "client");
+        assert_eq!(fs::read_to_string(dest.join("data/a.txt")).unwrap(), "a");
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn rejects_zip_entry_over_size_limit() {
+        let zip = zip_fixture(&[("data/a.txt", Some("too large"))]);
+
+        let dest = test_dir("size-limit");
+        let mut archive = ZipArchive::new(Cursor::new(zip)).unwrap();
+        let limits = ExtractLimits {
+            max_total_size: DEFAULT_MAX_TOTAL_SIZE,
+            max_entry_size: 4,
+        };
+        assert!(extract_zip(&mut archive, &dest, limits).is_err());
+        fs::remove_dir_all(&dest).unwrap();
+    }
+
+    #[test]
+    fn detects_distribution_from_directory_aliases() {
+        let detect = |path: &str| detect_distribution(Path::new(path));
+        assert_eq!(
+            detect("/mnt/archive/shaiya-us/patches"),
+            Some(Distribution::Us)
+        );
+        assert_eq!(
+            detect("/mnt/archive/Shaiya-Gamigo/patches"),
+            Some(Distribution::Ga)
+        );
+        assert_eq!(detect("/mnt/archive/shaiya-phoenix/patches"), None);
+        assert_eq!(detect("/mnt/archive/patches"), None);
+    }
+
+    #[test]
+    fn parses_patch_numbers_from_file_names() {
+        let parse = |name: &str| parse_patch_number(Path::new(name));
+        assert_eq!(parse("ps0430.patch"), Some(430));
+        assert_eq!(parse("Patch_1234.zip"), Some(1234));
+        assert_eq!(parse("ps12345.zip"), Some(12345));
+        assert_eq!(parse("shaiya-ps0001.zip"), Some(1));
+        assert_eq!(
+            parse("/mnt/archive/shaiya-us/patches/PS0430.patch"),
+            Some(430)
+        );
+        assert_eq!(parse("readme.zip"), None);
+        assert_eq!(parse("ps12.zip"), None);
+        assert_eq!(parse("maps0430.zip"), None);
+    }
+}
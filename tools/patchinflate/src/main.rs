@@ -1,116 +1,83 @@
-#![feature(io_error_more)]
-#![feature(is_some_with)]
-
-use anyhow::anyhow;
 use clap::Parser;
-use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
-use regex::Regex;
-use std::ffi::OsStr;
-use std::fs;
-use std::io::{BufReader, Write};
-use std::os::unix::prelude::OsStrExt;
-use std::path::{Path, PathBuf};
-use zip::{DateTime, ZipArchive};
+use common::config::{resolve, Config};
+use common::Distribution;
+use patchinflate::{ExtractLimits, InflateOptions, DEFAULT_MAX_ENTRY_SIZE, DEFAULT_MAX_TOTAL_SIZE};
+use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
 struct Args {
+    /// The config file to read default settings from.
+    #[clap(short, long, value_parser)]
+    config: Option<PathBuf>,
+
     /// The directory containing the patch files.
     #[clap(short, long, value_parser)]
-    patch_dir: PathBuf,
+    patch_dir: Option<PathBuf>,
 
     /// The directory to extract the patch files to.
     #[clap(short, long, value_parser)]
-    inflate_dir: PathBuf,
+    inflate_dir: Option<PathBuf>,
+
+    /// The distribution the patches belong to (i.e. `us`). If not provided, this is detected from
+    /// a `shaiya-<dist>` component of the patch directory.
+    #[clap(short, long, value_parser)]
+    dist: Option<Distribution>,
+
+    /// The number of threads to inflate patches with. Defaults to the number of cores.
+    #[clap(short, long, value_parser)]
+    threads: Option<usize>,
+
+    /// Reconcile inflated directories that share a patch number but have different dates,
+    /// merging identical ones and reporting any that differ.
+    #[clap(long, value_parser)]
+    dedup: bool,
+
+    /// A file name of the game client, matched case-insensitively. Can be passed more than once.
+    /// Defaults to `game.exe` and `shaiya.exe`.
+    #[clap(long = "client-name", value_parser)]
+    client_names: Vec<String>,
+
+    /// Re-inflate patches that have already been inflated, rather than skipping them.
+    #[clap(long, value_parser)]
+    force: bool,
+
+    /// The most a single patch can be extracted to, in bytes. A patch which exceeds it fails to
+    /// inflate, without stopping the others.
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_TOTAL_SIZE)]
+    max_size: u64,
+
+    /// The most a single file in a patch can be extracted to, in bytes.
+    #[clap(long, value_parser, default_value_t = DEFAULT_MAX_ENTRY_SIZE)]
+    max_entry_size: u64,
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
     let args = Args::parse();
-
-    // If the `patch_dir` is not a valid directory, we should return early.
-    if let Ok(metadata) = fs::metadata(&args.patch_dir) {
-        if !metadata.is_dir() {
-            return Err(anyhow!(std::io::ErrorKind::NotADirectory));
+    let config = Config::load_or_default(args.config.as_deref())?;
+    let input_dir = resolve(args.patch_dir, config.patch_dir, "patch_dir")?;
+    let inflate_dir = resolve(args.inflate_dir, config.inflate_dir, "inflate_dir")?;
+    let threads = args.threads.or(config.concurrency).unwrap_or(0);
+
+    let output = patchinflate::inflate(&InflateOptions {
+        patch_dir: input_dir,
+        inflate_dir,
+        dist: args.dist,
+        threads,
+        client_names: args.client_names,
+        force: args.force,
+        limits: ExtractLimits {
+            max_total_size: args.max_size,
+            max_entry_size: args.max_entry_size,
+        },
+    })?;
+
+    if args.dedup {
+        for reconciliation in patchinflate::dedup_patches(&output.patch_dir)? {
+            println!("{}", reconciliation);
         }
     }
-
-    // Create the output directories.
-    let patch_dir = args.inflate_dir.join("patches");
-    let client_dir = args.inflate_dir.join("clients");
-    fs::create_dir_all(&patch_dir)?;
-    fs::create_dir_all(&client_dir)?;
-
-    // Collect all of the patch files in the input directory.
-    let patches = fs::read_dir(&args.patch_dir)?
-        .filter_map(Result::ok)
-        .filter(|d| d.metadata().is_ok_and(|m| m.is_file()))
-        .filter(|d| d.path().extension() == Some(OsStr::from_bytes(b"patch")))
-        .map(|d| d.path())
-        .collect::<Vec<_>>();
-
-    // Iterate over each patch and inflate it.
-    patches.par_iter().for_each(|path| {
-        inflate_patch(path, &patch_dir, &client_dir).expect("failed to inflate patch");
-    });
-    Ok(())
-}
-
-fn inflate_patch(path: &Path, patch_dir: &Path, client_dir: &Path) -> anyhow::Result<()> {
-    let re = Regex::new(r"(ps\d{4})")?;
-    let captures = re.captures(path.to_str().unwrap()).unwrap();
-
-    let patch = captures.get(1).unwrap().as_str();
-    let file = fs::File::open(path)?;
-
-    // Parse the patch file as a zip archive.
-    let reader = BufReader::new(&file);
-    let mut zip = ZipArchive::new(reader)?;
-
-    // Find the most recent date within the archive.
-    let mut date = DateTime::default();
-    (0..zip.len()).for_each(|idx| {
-        if let Ok(file) = zip.by_index(idx) {
-            if file.last_modified().to_time().unwrap() > date.to_time().unwrap() {
-                date = file.last_modified();
-            }
-        }
-    });
-
-    // Include the most recent date in the patch name.
-    let patch_name = format!("{}-{}-{}-{}", patch, date.day(), date.month(), date.year());
-
-    // Create the output directory.
-    let patch_out_dir = patch_dir.join(&patch_name);
-    fs::create_dir_all(&patch_out_dir)?;
-
-    // Copy the patch file, to the patch directory.
-    fs::copy(
-        path,
-        patch_out_dir.join(path.file_name().unwrap().to_str().unwrap()),
-    )?;
-
-    // Extract the contents of the patch, to the destination
-    zip_extract::extract(BufReader::new(&file), &patch_out_dir, true).map_err(|e| anyhow!(e))?;
-
-    // If the patch contains an archive filesystem, we'll extract it.
-    let header_file = patch_out_dir.join("update.sah");
-    let data_file = patch_out_dir.join("update.saf");
-    if header_file.is_file() {
-        let fs = libclient::fs::Filesystem::from_archive(&header_file, &data_file)?;
-        fs.extract(&patch_out_dir.join("data"))?;
-
-        // Delete the archive files.
-        fs::remove_file(&header_file)?;
-        fs::remove_file(&data_file)?;
-    }
-
-    // If the patch contains a game client, we'll create a copy in the `client_dir`
-    let client_file = patch_out_dir.join("game.exe");
-    if client_file.is_file() {
-        let client_buf = fs::read(&client_file)?;
-        let mut client = fs::File::create(&client_dir.join(format!("{}-game.exe", patch_name)))?;
-        client.write_all(&client_buf)?;
-    }
     Ok(())
 }